@@ -0,0 +1,133 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::{Context, Error};
+use fs2::FileExt;
+use indicatif::MultiProgress;
+use sha2::{Digest, Sha256};
+use url::Url;
+use crate::log;
+use crate::types::ArchiveFormat;
+
+const ARCHIVES_DIR: &str = "archives";
+const URLS_DIR: &str = "urls";
+const PARTIAL_DIR: &str = "partial";
+const LOCKS_DIR: &str = "locks";
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// User-level archive cache shared by every project.
+///
+/// Archives are stored by their sha256 and format extension under `archives/`. `urls/` maps the hash of a
+/// download url to the checksum of what it served, so archives without a checksum in
+/// the registry are found again too. `partial/` holds unfinished downloads.
+#[derive(Debug, Clone)]
+pub struct ArchiveCache
+{
+  root: PathBuf
+}
+
+/// Exclusive lock on one url in the cache, released on drop.
+#[derive(Debug)]
+pub struct CacheLock
+{
+  file: File
+}
+
+impl Drop for CacheLock
+{
+  fn drop(&mut self)
+  {
+    let _ = self.file.unlock();
+  }
+}
+
+impl ArchiveCache
+{
+  pub fn new(root: &str) -> Result<Self, Error>
+  {
+    let root = PathBuf::from(root);
+    for dir in [ARCHIVES_DIR, URLS_DIR, PARTIAL_DIR, LOCKS_DIR] {
+      std::fs::create_dir_all(root.join(dir))
+        .with_context(|| format!("failed to create cache directory at {}", root.display()))?;
+    }
+    Ok(Self { root })
+  }
+
+  pub fn archive_path(&self, checksum: &str, format: ArchiveFormat) -> PathBuf
+  {
+    self.root
+      .join(ARCHIVES_DIR)
+      .join(format!("{}.{}", checksum, format.extension()))
+  }
+
+  pub fn partial_path(&self, url: &Url) -> PathBuf
+  {
+    self.root
+      .join(PARTIAL_DIR)
+      .join(format!("{}.part", Self::url_key(url)))
+  }
+
+  /// Holds the `ETag` or `Last-Modified` of what the partial download at `partial` came from.
+  pub fn validator_path(partial: &Path) -> PathBuf
+  {
+    partial.with_extension("validator")
+  }
+
+  /// Finds a cached archive by its checksum or, when that is unknown, by the url it came from.
+  pub fn find(&self, url: &Url, checksum: Option<&str>) -> Option<PathBuf>
+  {
+    let checksum = match checksum {
+      Some(checksum) => checksum.to_string(),
+      None => std::fs::read_to_string(self.root.join(URLS_DIR).join(Self::url_key(url)))
+        .ok()?
+        .trim()
+        .to_string()
+    };
+    ArchiveFormat::ALL
+      .into_iter()
+      .map(|format| self.archive_path(checksum.as_str(), format))
+      .find(|path| path.is_file())
+  }
+
+  /// Moves a finished download into the cache and returns its final path.
+  pub fn store(&self, url: &Url, partial: &Path, checksum: &str, format: ArchiveFormat) -> Result<PathBuf, Error>
+  {
+    let path = self.archive_path(checksum, format);
+    std::fs::rename(partial, &path)?;
+    let validator = Self::validator_path(partial);
+    if validator.exists() {
+      std::fs::remove_file(validator)?;
+    }
+    std::fs::write(self.root.join(URLS_DIR).join(Self::url_key(url)), checksum)?;
+    Ok(path)
+  }
+
+  /// Waits, polling, until no other fdm process or download works on `url`.
+  pub async fn lock(&self, url: &Url, progress: &MultiProgress) -> Result<CacheLock, Error>
+  {
+    let path = self.root
+      .join(LOCKS_DIR)
+      .join(format!("{}.lock", Self::url_key(url)));
+    let file = File::create(&path)
+      .with_context(|| format!("failed to create cache lock {}", path.display()))?;
+    let mut waiting = false;
+    loop {
+      match file.try_lock_exclusive() {
+        Ok(()) => return Ok(CacheLock { file }),
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+          if !waiting {
+            progress.suspend(|| { log!("waiting for cache lock on {}", url); });
+            waiting = true;
+          }
+          tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+        },
+        Err(e) => return Err(Error::from(e).context(format!("failed to lock {}", path.display())))
+      }
+    }
+  }
+
+  fn url_key(url: &Url) -> String
+  {
+    format!("{:x}", Sha256::digest(url.as_str().as_bytes()))
+  }
+}
@@ -6,13 +6,50 @@ pub struct Args {
   /// Load all dependencies for the current project from fdm.toml file
   #[arg(short, long)] pub load: bool,
 
+  /// Shows versions, artifacts, link metadata and dependencies of a package in the registries
+  #[arg(long)] pub info: Option<String>,
+
   /// Overrides default registry url: https://github.com/fdm2-org/fdm-registry
   #[arg(long)] pub registry: Option<String>,
 
+  /// Branch, tag or commit sha of the registry to use. Defaults to main
+  #[arg(long)] pub registry_ref: Option<String>,
+
+  /// Maximum number of dependencies downloaded at the same time. Defaults to 4
+  #[arg(short, long)] pub jobs: Option<usize>,
+
+  /// How many times a download is attempted before giving up. Defaults to 3
+  #[arg(long)] pub retries: Option<u32>,
+
+  /// Directory of the download cache shared between projects. Defaults to $FDM_CACHE_DIR
+  /// or the user cache directory (e.g. $XDG_CACHE_HOME/fdm)
+  #[arg(long)] pub cache_dir: Option<String>,
+
+  /// Reinstalls every dependency, even those already unpacked in fdm/pack/libs
+  #[arg(long)] pub force: bool,
+
+  /// Proxy for all http(s) requests and git registries. Defaults to $FDM_PROXY, then the user config, then $HTTPS_PROXY / $HTTP_PROXY
+  #[arg(long)] pub proxy: Option<String>,
+
+  /// Comma-separated hosts that bypass the proxy. Defaults to $FDM_NO_PROXY, then the user config, then $NO_PROXY
+  #[arg(long)] pub no_proxy: Option<String>,
+
+  /// PEM file with extra root certificates to trust. Defaults to $FDM_CA_BUNDLE, then the user config
+  #[arg(long)] pub ca_bundle: Option<String>,
+
+  /// Connect timeout in seconds. Defaults to $FDM_CONNECT_TIMEOUT or 10
+  #[arg(long)] pub connect_timeout: Option<u64>,
+
+  /// Seconds to wait for more data of a response before retrying. Defaults to $FDM_READ_TIMEOUT
+  #[arg(long)] pub read_timeout: Option<u64>,
+
+  /// User agent for http requests, or "random" for a random browser one. Defaults to $FDM_USER_AGENT
+  #[arg(long)] pub user_agent: Option<String>,
+
   /// Forces fdm to use local offline registry
   #[arg(long)] pub offline: bool,
 
-  /// Specifies the path to local registry
+  /// Specifies the path to local registry: a directory with yaml files or a local git repository
   #[arg(long)] pub local: Option<String>,
 
   /// Specifies target system. Useful for cross-compiling
@@ -1,11 +1,7 @@
 pub mod parser;
 pub mod commands;
+pub mod targets;
 
 pub use parser::CMakeFile;
-pub use commands::
-{
-  CMakeCommand,
-  FileGlobMode,
-  FileRelativeMode,
-  ListMode
-};
\ No newline at end of file
+pub use targets::ImportedTarget;
+pub use commands::CMakeCommand;
@@ -1,43 +1,100 @@
-use std::fs::File;
-use std::io::Write;
-use anyhow::Error;
+use std::path::Path;
+use anyhow::{bail, Context, Error};
+use colored::Colorize;
 use crate::cmake::CMakeCommand;
+use crate::generated::write_if_changed;
+use crate::warn;
 
+pub const FDM_BEGIN_MARKER: &str = "# fdm:begin";
+pub const FDM_END_MARKER: &str = "# fdm:end";
+
+/// A CMake file fdm generates into. Only the region between `# fdm:begin` and `# fdm:end`
+/// is fdm's: `head` and `tail` around it are kept as they are.
 pub struct CMakeFile
 {
   pub path: String,
-  pub content: String
+  pub head: String,
+  pub content: String,
+  pub tail: String
 }
 
 impl CMakeFile
 {
-  #[allow(dead_code)]
+  /// Reads what surrounds the generated region of `path`. A file without markers is
+  /// usually the output of an older fdm, so it is replaced, but backed up first.
   pub fn from_file(path: &str) -> Result<Self, Error>
   {
+    let existing = match Path::new(path).exists() {
+      true => std::fs::read_to_string(path)?,
+      false => String::new()
+    };
+    let lines = existing
+      .split_inclusive('\n')
+      .collect::<Vec<&str>>();
+    let find = |marker: &str| lines
+      .iter()
+      .enumerate()
+      .filter(|(_, line)| line.trim() == marker)
+      .map(|(index, _)| index)
+      .collect::<Vec<usize>>();
+    let (head, tail) = match (find(FDM_BEGIN_MARKER).as_slice(), find(FDM_END_MARKER).as_slice()) {
+      ([], []) => {
+        if !existing.trim().is_empty() {
+          let backup = Self::backup_path(path);
+          write_if_changed(Path::new(&backup), &existing)
+            .with_context(|| format!("failed to back up {}", path))?;
+          warn!("{} has no {} / {} markers, its content was moved to {}. \
+                 move your own additions back outside the markers",
+            path,
+            FDM_BEGIN_MARKER,
+            FDM_END_MARKER,
+            backup
+          );
+        }
+        (String::new(), String::new())
+      },
+      ([begin], [end]) if begin < end => {
+        let mut head = lines[..*begin].concat();
+        if !head.is_empty() && !head.ends_with('\n') {
+          head.push('\n');
+        }
+        (head, lines[end + 1..].concat())
+      },
+      _ => bail!("{} must contain exactly one {} followed by one {}, fix or delete the file",
+        path,
+        FDM_BEGIN_MARKER,
+        FDM_END_MARKER
+      )
+    };
     Ok(Self
     {
       path: path.to_string(),
-      content: std::fs::read_to_string(&path)?
+      head,
+      content: String::new(),
+      tail
     })
   }
 
-  pub fn new(path: &str) -> Result<Self, Error>
+  /// `<path>.bak`, or `<path>.bak.<n>` with the first free `n`, so earlier backups are kept.
+  fn backup_path(path: &str) -> String
   {
-    if std::path::Path::new(&path).exists() {
-      std::fs::remove_file(&path)?;
-    }
-    Ok(Self
-    {
-      path: path.to_string(),
-      content: String::new()
-    })
+    let backup = format!("{}.bak", path);
+    std::iter::once(backup.clone())
+      .chain((1..).map(|n| format!("{}.{}", backup, n)))
+      .find(|candidate| !Path::new(candidate).exists())
+      .unwrap()
   }
 
-  pub fn commit(&self) -> Result<(), Error>
+  /// Writes the file if its content changed and tells whether it did.
+  pub fn commit(&self) -> Result<bool, Error>
   {
-    let mut file = File::create(&self.path)?;
-    file.write_all(self.content.as_bytes())?;
-    Ok(())
+    write_if_changed(Path::new(&self.path), format!("{}{}\n{}{}\n{}",
+      self.head,
+      FDM_BEGIN_MARKER,
+      self.content,
+      FDM_END_MARKER,
+      self.tail
+    ).as_str())
   }
 
   pub fn command(&mut self, cmd: CMakeCommand) -> Result<&mut Self, Error>
@@ -47,3 +104,69 @@ impl CMakeFile
   }
 }
 
+#[cfg(test)]
+mod tests
+{
+  use std::path::PathBuf;
+  use super::*;
+
+  fn scratch(name: &str) -> PathBuf
+  {
+    let dir = std::env::temp_dir().join(format!("fdm-cmake-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  #[test]
+  fn keeps_content_around_markers()
+  {
+    let dir = scratch("markers");
+    let path = dir.join("CMakeLists.txt");
+    let path = path.to_str().unwrap();
+    std::fs::write(path, "project(app)\n# fdm:begin\nadd_subdirectory(old)\n  # fdm:end\nadd_executable(app main.cpp)\n").unwrap();
+    let mut file = CMakeFile::from_file(path).unwrap();
+    assert_eq!(file.head, "project(app)\n");
+    assert_eq!(file.tail, "add_executable(app main.cpp)\n");
+    file.command(CMakeCommand::add_subdirectory("pack".to_string())).unwrap();
+    assert!(file.commit().unwrap());
+    let written = std::fs::read_to_string(path).unwrap();
+    assert_eq!(written, "project(app)\n# fdm:begin\nadd_subdirectory(pack)\n# fdm:end\nadd_executable(app main.cpp)\n");
+    // reading back and generating the same content again changes nothing
+    let mut file = CMakeFile::from_file(path).unwrap();
+    file.command(CMakeCommand::add_subdirectory("pack".to_string())).unwrap();
+    assert!(!file.commit().unwrap());
+    assert_eq!(std::fs::read_to_string(path).unwrap(), written);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn backs_up_files_without_markers()
+  {
+    let dir = scratch("backup");
+    let path = dir.join("CMakeLists.txt");
+    let path = path.to_str().unwrap();
+    assert!(CMakeFile::from_file(path).unwrap().head.is_empty());
+    std::fs::write(path, "first\n").unwrap();
+    CMakeFile::from_file(path).unwrap();
+    std::fs::write(path, "second\n").unwrap();
+    let file = CMakeFile::from_file(path).unwrap();
+    assert!(file.head.is_empty() && file.tail.is_empty());
+    assert_eq!(std::fs::read_to_string(format!("{}.bak", path)).unwrap(), "first\n");
+    assert_eq!(std::fs::read_to_string(format!("{}.bak.1", path)).unwrap(), "second\n");
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn rejects_misplaced_markers()
+  {
+    let dir = scratch("misplaced");
+    let path = dir.join("CMakeLists.txt");
+    let path = path.to_str().unwrap();
+    for content in ["# fdm:end\n# fdm:begin\n", "# fdm:begin\n", "# fdm:begin\n# fdm:end\n# fdm:begin\n# fdm:end\n"] {
+      std::fs::write(path, content).unwrap();
+      assert!(CMakeFile::from_file(path).is_err(), "{:?} should be rejected", content);
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
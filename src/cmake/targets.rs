@@ -0,0 +1,322 @@
+use std::path::{Path, PathBuf};
+use anyhow::{ensure, Context, Error};
+use colored::Colorize;
+use crate::cmake::CMakeCommand;
+use crate::types::dependencies::ResolvedDependency;
+use crate::types::{Distribution, LinkInfo, PlatformArch};
+use crate::warn;
+
+pub const TARGET_NAMESPACE: &str = "fdm::";
+
+// searched in this order, so the conventional locations win
+const LIBRARY_DIRS: [&str; 4] = ["lib", "lib64", "bin", "."];
+
+/// `fdm::<name>` IMPORTED target of a prebuilt (static or shared) package, or of one of
+/// its components (`fdm::<name>::<component>`). Paths are relative to the directory holding all packages.
+#[derive(Debug)]
+pub struct ImportedTarget
+{
+  pub name: String,
+  pub distribution: Distribution,
+  pub location: Option<PathBuf>,
+  pub implib: Option<PathBuf>,
+  pub libraries: Vec<PathBuf>,
+  pub include_dirs: Vec<PathBuf>,
+  pub defines: Vec<String>,
+  pub system_libs: Vec<String>,
+  /// CMake targets linked along, `fdm::<name>` or the own target of a package built from sources.
+  pub dependencies: Vec<String>
+}
+
+/// Main library, import library and any further libraries of a target.
+type Libraries = (Option<PathBuf>, Option<PathBuf>, Vec<PathBuf>);
+
+impl ImportedTarget
+{
+  pub fn target_name(name: &str) -> String
+  {
+    format!("{}{}", TARGET_NAMESPACE, name)
+  }
+
+  /// Targets of an installed package: the package itself, then its components. Headers and
+  /// libraries come from the link metadata of the registry or else the usual layout
+  /// (`include/`, `lib/`, `bin/`). Packages built from sources bring their own targets.
+  pub fn discover(dependency: &ResolvedDependency,
+                  link: &LinkInfo,
+                  libs_dir: &Path,
+                  dependencies: Vec<String>) -> Result<Vec<Self>, Error>
+  {
+    if !matches!(dependency.distribution, Distribution::Static | Distribution::Shared) {
+      return Ok(Vec::new());
+    }
+    let package_dir = libs_dir.join(&dependency.name);
+    let windows = matches!(dependency.arch, PlatformArch::WindowsX32 | PlatformArch::WindowsX64);
+    let (extension, implib_extension) = match (&dependency.distribution, windows) {
+      (Distribution::Static, true) => ("lib", None),
+      (Distribution::Static, false) => ("a", None),
+      (_, true) => ("dll", Some("lib")),
+      (_, false) => ("so", None)
+    };
+    let (location, implib, libraries) = match (link.libs.is_empty(), link.components.is_empty()) {
+      (false, _) => Self::named_libraries(&package_dir, &link.libs, extension, implib_extension)?,
+      // the components are what links, the package only carries headers and flags
+      (true, false) => (None, None, Vec::new()),
+      (true, true) => Self::discover_libraries(dependency, &package_dir, extension, implib_extension)?
+    };
+    let include_dirs = match link.include.is_empty() {
+      true => {
+        let include_dir = package_dir.join("include");
+        vec![match include_dir.is_dir() {
+          true => include_dir,
+          false => package_dir.clone()
+        }]
+      },
+      false => link.include
+        .iter()
+        .map(|dir| {
+          let include_dir = package_dir.join(dir);
+          ensure!(include_dir.is_dir(), "include directory {} of {} not found in {}", dir, dependency.name, package_dir.display());
+          Ok(include_dir)
+        })
+        .collect::<Result<Vec<PathBuf>, Error>>()?
+    };
+    let mut targets = vec![Self
+    {
+      name: dependency.name.clone(),
+      distribution: dependency.distribution.clone(),
+      location,
+      implib,
+      libraries,
+      include_dirs,
+      defines: link.defines.clone(),
+      system_libs: link.system_libs.clone(),
+      dependencies
+    }];
+    for (component, libs) in &link.components {
+      let (location, implib, libraries) = Self::named_libraries(&package_dir, libs, extension, implib_extension)
+        .with_context(|| format!("invalid component {} of {}", component, dependency.name))?;
+      targets.push(Self
+      {
+        name: format!("{}::{}", dependency.name, component),
+        distribution: dependency.distribution.clone(),
+        location,
+        implib,
+        libraries,
+        include_dirs: Vec::new(),
+        defines: Vec::new(),
+        system_libs: Vec::new(),
+        dependencies: vec![Self::target_name(&dependency.name)]
+      });
+    }
+    Ok(targets
+      .into_iter()
+      .map(|target| target.relative_to(libs_dir))
+      .collect())
+  }
+
+  pub fn commands(&self) -> Vec<CMakeCommand>
+  {
+    let target = Self::target_name(&self.name);
+    let kind = match (&self.location, &self.distribution) {
+      (None, _) => "INTERFACE",
+      (Some(_), Distribution::Shared) => "SHARED",
+      (Some(_), _) => "STATIC"
+    };
+    let mut properties = Vec::new();
+    if let Some(location) = &self.location {
+      properties.push(("IMPORTED_LOCATION".to_string(), Self::quoted(&[Self::cmake_path(location)])));
+    }
+    if let Some(implib) = &self.implib {
+      properties.push(("IMPORTED_IMPLIB".to_string(), Self::quoted(&[Self::cmake_path(implib)])));
+    }
+    if !self.include_dirs.is_empty() {
+      properties.push(("INTERFACE_INCLUDE_DIRECTORIES".to_string(), Self::quoted(&self.include_dirs
+        .iter()
+        .map(|dir| Self::cmake_path(dir))
+        .collect::<Vec<String>>()
+      )));
+    }
+    if !self.defines.is_empty() {
+      properties.push(("INTERFACE_COMPILE_DEFINITIONS".to_string(), Self::quoted(&self.defines)));
+    }
+    // system libraries go last, after everything that may need them
+    let link_libraries = self.libraries
+      .iter()
+      .map(|library| Self::cmake_path(library))
+      .chain(self.dependencies.iter().cloned())
+      .chain(self.system_libs.iter().cloned())
+      .collect::<Vec<String>>();
+    if !link_libraries.is_empty() {
+      properties.push(("INTERFACE_LINK_LIBRARIES".to_string(), Self::quoted(&link_libraries)));
+    }
+    vec![
+      CMakeCommand::add_library_imported(target.clone(), kind.to_string()),
+      CMakeCommand::set_target_properties(target, properties)
+    ]
+  }
+
+  /// Libraries listed by the registry. A name with a path or extension is a file in the
+  /// package, any other is looked up like `libname.a` / `name.lib` / `libname.so`.
+  fn named_libraries(package_dir: &Path,
+                     names: &[String],
+                     extension: &str,
+                     implib_extension: Option<&str>) -> Result<Libraries, Error>
+  {
+    let find = |name: &str, extension: &str| -> Result<PathBuf, Error> {
+      if name.contains('/') || name.ends_with(&format!(".{}", extension)) {
+        let path = package_dir.join(name);
+        ensure!(path.is_file(), "library {} not found in {}", name, package_dir.display());
+        return Ok(path);
+      }
+      Self::find_libraries(package_dir, extension)?
+        .into_iter()
+        .find(|path| Self::library_stem(path, extension).is_some_and(|stem| stem == name))
+        .with_context(|| format!("no .{} library named {} found in {}", extension, name, package_dir.display()))
+    };
+    let mut paths = names
+      .iter()
+      .map(|name| find(name, extension))
+      .collect::<Result<Vec<PathBuf>, Error>>()?;
+    let location = paths.remove(0);
+    match implib_extension {
+      // a dll is linked through its import library, and so are the other libraries
+      Some(implib_extension) => {
+        let mut implibs = names
+          .iter()
+          .map(|name| find(
+            Self::library_stem(Path::new(name), extension).as_deref().unwrap_or(name),
+            implib_extension
+          ))
+          .collect::<Result<Vec<PathBuf>, Error>>()?;
+        let implib = implibs.remove(0);
+        Ok((Some(location), Some(implib), implibs))
+      },
+      None => Ok((Some(location), None, paths))
+    }
+  }
+
+  /// Libraries found by file extension, the one named after the package first.
+  fn discover_libraries(dependency: &ResolvedDependency,
+                        package_dir: &Path,
+                        extension: &str,
+                        implib_extension: Option<&str>) -> Result<Libraries, Error>
+  {
+    let mut libraries = Self::find_libraries(package_dir, extension)?;
+    let location = Self::take_main(&mut libraries, &dependency.name, extension);
+    if location.is_none() {
+      warn!("no .{} library found in {}, {} will only provide include directories",
+        extension,
+        package_dir.display(),
+        Self::target_name(&dependency.name)
+      );
+    }
+    Ok(match implib_extension {
+      Some(implib_extension) => {
+        let mut implibs = Self::find_libraries(package_dir, implib_extension)?;
+        (location, Self::take_main(&mut implibs, &dependency.name, implib_extension), implibs)
+      },
+      None => (location, None, libraries)
+    })
+  }
+
+  fn relative_to(self, libs_dir: &Path) -> Self
+  {
+    let relative = |path: PathBuf| path
+      .strip_prefix(libs_dir)
+      .map(Path::to_path_buf)
+      .unwrap_or(path);
+    Self
+    {
+      location: self.location.map(relative),
+      implib: self.implib.map(relative),
+      libraries: self.libraries
+        .into_iter()
+        .map(relative)
+        .collect(),
+      include_dirs: self.include_dirs
+        .into_iter()
+        .map(relative)
+        .collect(),
+      ..self
+    }
+  }
+
+  /// Library files with `extension` in the library directories of a package, one per library
+  /// name. A versioned shared object (`libx.so.1`) only counts without an unversioned one.
+  fn find_libraries(package_dir: &Path, extension: &str) -> Result<Vec<PathBuf>, Error>
+  {
+    let mut candidates = Vec::new();
+    for dir in LIBRARY_DIRS {
+      let dir = package_dir.join(dir);
+      if !dir.is_dir() {
+        continue;
+      }
+      let mut found = std::fs::read_dir(&dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<PathBuf>, _>>()?
+        .into_iter()
+        // symlinks in archives are unpacked as empty files
+        .filter(|path| path.metadata().is_ok_and(|meta| meta.is_file() && meta.len() > 0))
+        .filter(|path| Self::library_stem(path, extension).is_some())
+        .collect::<Vec<PathBuf>>();
+      found.sort();
+      candidates.extend(found);
+    }
+    let versioned = |path: &PathBuf| !path.to_string_lossy().ends_with(&format!(".{}", extension));
+    // stable sort: unversioned first, directory order otherwise kept
+    candidates.sort_by_key(versioned);
+    let mut libraries: Vec<PathBuf> = Vec::new();
+    for path in candidates {
+      let stem = Self::library_stem(&path, extension);
+      if !libraries
+        .iter()
+        .any(|known| Self::library_stem(known, extension) == stem)
+      {
+        libraries.push(path);
+      }
+    }
+    Ok(libraries)
+  }
+
+  /// `foo` for `libfoo.a`, `foo.lib` and `libfoo.so.1.2`.
+  fn library_stem(path: &Path, extension: &str) -> Option<String>
+  {
+    let file_name = path.file_name()?.to_string_lossy().to_string();
+    let suffix = format!(".{}", extension);
+    let stem = match file_name.find(&format!("{}.", suffix)) {
+      Some(pos) if extension == "so" => &file_name[..pos],
+      _ => file_name.strip_suffix(&suffix)?
+    };
+    Some(stem
+      .strip_prefix("lib")
+      .filter(|stem| !stem.is_empty())
+      .unwrap_or(stem)
+      .to_string())
+  }
+
+  /// Removes and returns the library named after the package, or else the first one.
+  fn take_main(libraries: &mut Vec<PathBuf>, name: &str, extension: &str) -> Option<PathBuf>
+  {
+    if libraries.is_empty() {
+      return None;
+    }
+    let index = libraries
+      .iter()
+      .position(|path| Self::library_stem(path, extension).is_some_and(|stem| stem == name))
+      .unwrap_or(0);
+    Some(libraries.remove(index))
+  }
+
+  fn cmake_path(path: &Path) -> String
+  {
+    match path.to_string_lossy().replace('\\', "/").as_str() {
+      "" | "." => "${CMAKE_CURRENT_SOURCE_DIR}".to_string(),
+      path => format!("${{CMAKE_CURRENT_SOURCE_DIR}}/{}", path.trim_start_matches("./"))
+    }
+  }
+
+  fn quoted(values: &[String]) -> String
+  {
+    format!("\"{}\"", values.join(";"))
+  }
+}
@@ -1,8 +1,17 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
-use anyhow::Error;
+use std::time::Duration;
+use anyhow::{Context, Error};
 use colored::Colorize;
 use lazy_static::lazy_static;
+use serde::Deserialize;
+use url::Url;
 use crate::{fatal_error, log, warn};
+use crate::consts::{FDM_CACHE_NAME, FDM_DIRECTORY_NAME, FDM_NAME};
+use crate::registry::{RegistrySpec, SourceKind, DEFAULT_REGISTRY_NAME};
+use crate::registry::git::DEFAULT_BRANCH;
+use crate::registry::client_builder::{ClientConfig, UserAgent};
 use crate::types::PlatformArch;
 
 lazy_static!
@@ -15,9 +24,87 @@ pub struct Config
 {
   pub offline_registry_url: Option<String>,
   pub online_registry_url: String,
-  pub platform: PlatformArch
+  pub registry_ref: Option<String>,
+  pub registries: Vec<RegistrySpec>,
+  pub platform: PlatformArch,
+  pub jobs: usize,
+  pub retries: u32,
+  pub cache_dir: Option<String>,
+  pub force: bool,
+  pub mirrors: BTreeMap<String, String>,
+  pub http_args: HttpSettingsPT,
+  pub http_file: HttpSettingsPT
 }
 
+/// Http client settings as given on one level: command line, environment or config file.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct HttpSettingsPT
+{
+  pub proxy: Option<String>,
+  pub no_proxy: Option<String>,
+  pub ca_bundle: Option<String>,
+  pub connect_timeout: Option<u64>,
+  pub read_timeout: Option<u64>,
+  pub user_agent: Option<String>
+}
+
+impl HttpSettingsPT
+{
+  fn from_env() -> Result<Self, Error>
+  {
+    let var = |name: &str| std::env::var(name)
+      .ok()
+      .filter(|value| !value.is_empty());
+    let seconds = |name: &str| var(name)
+      .map(|value| value
+        .parse::<u64>()
+        .with_context(|| format!("{} must be a number of seconds, got: {}", name, value))
+      )
+      .transpose();
+    Ok(Self
+    {
+      proxy: var("FDM_PROXY"),
+      no_proxy: var("FDM_NO_PROXY"),
+      ca_bundle: var("FDM_CA_BUNDLE"),
+      connect_timeout: seconds("FDM_CONNECT_TIMEOUT")?,
+      read_timeout: seconds("FDM_READ_TIMEOUT")?,
+      user_agent: var("FDM_USER_AGENT")
+    })
+  }
+
+  /// Fills whatever is not set here from `fallback`.
+  fn or(self, fallback: Self) -> Self
+  {
+    Self
+    {
+      proxy: self.proxy.or(fallback.proxy),
+      no_proxy: self.no_proxy.or(fallback.no_proxy),
+      ca_bundle: self.ca_bundle.or(fallback.ca_bundle),
+      connect_timeout: self.connect_timeout.or(fallback.connect_timeout),
+      read_timeout: self.read_timeout.or(fallback.read_timeout),
+      user_agent: self.user_agent.or(fallback.user_agent)
+    }
+  }
+}
+
+/// User-wide settings from `~/.config/fdm/config.toml` (or `$FDM_CONFIG`).
+#[derive(Debug, Default, Deserialize)]
+struct UserConfigPT
+{
+  /// Url prefix rewrites for artifact downloads, e.g. `"https://github.com/" = "https://artifacts.corp/github/"`
+  #[serde(default)]
+  pub mirrors: BTreeMap<String, String>,
+  #[serde(default)]
+  pub http: HttpSettingsPT
+}
+
+const DEFAULT_JOBS: usize = 4;
+const DEFAULT_RETRIES: u32 = 3;
+const CACHE_DIR_ENV: &str = "FDM_CACHE_DIR";
+const USER_CONFIG_ENV: &str = "FDM_CONFIG";
+const USER_CONFIG_NAME: &str = "config.toml";
+
 impl Default for Config
 {
   fn default() -> Self
@@ -26,7 +113,16 @@ impl Default for Config
     {
       offline_registry_url: None,
       online_registry_url: String::from("https://github.com/fdm2-org/fdm-registry"),
-      platform: PlatformArch::from_env().unwrap_or_default()
+      registry_ref: None,
+      registries: Vec::new(),
+      platform: PlatformArch::from_env().unwrap_or_default(),
+      jobs: DEFAULT_JOBS,
+      retries: DEFAULT_RETRIES,
+      cache_dir: None,
+      force: false,
+      mirrors: BTreeMap::new(),
+      http_args: HttpSettingsPT::default(),
+      http_file: HttpSettingsPT::default()
     }
   }
 }
@@ -39,18 +135,149 @@ impl Config
     log!("{}", "-- configuration --".green().bold());
     if self.offline_registry_url.is_some()
     {
-      warn!("using offline registry!");
-      log!("offline registry url: {}", self.offline_registry_url.as_ref().unwrap());
+      warn!("using offline registry! only file:// urls and cached archives can be downloaded");
+      log!("offline registry path: {}", self.offline_registry_url.as_ref().unwrap());
     }
     else
     {
       log!("using online registry by default");
       log!("online registry url: {}", self.online_registry_url);
+      log!("registry ref: {}", self.registry_ref());
     }
     log!("platform: {}", self.platform.to_string().as_str().purple().bold());
+    log!("parallel downloads: {}", self.jobs);
+    log!("download cache: {}", self.cache_dir());
     println!();
   }
 
+  /// Download cache directory: `--cache-dir`, then `FDM_CACHE_DIR`, then the user cache
+  /// directory of the platform. Falls back to the project directory if there is none.
+  pub fn cache_dir(&self) -> String
+  {
+    self.cache_dir
+      .clone()
+      .or_else(|| std::env::var(CACHE_DIR_ENV).ok().filter(|dir| !dir.is_empty()))
+      .or_else(|| dirs::cache_dir()
+        .map(|dir| dir.join(FDM_NAME))
+        .and_then(|dir| dir.into_os_string().into_string().ok())
+      )
+      .unwrap_or_else(|| Path::new(&wd().unwrap_or_default())
+        .join(FDM_DIRECTORY_NAME)
+        .join(FDM_CACHE_NAME)
+        .to_string_lossy()
+        .to_string()
+      )
+  }
+
+  /// Branch, tag or commit sha of the registry repository to check out.
+  pub fn registry_ref(&self) -> &str
+  {
+    self.registry_ref
+      .as_deref()
+      .unwrap_or(DEFAULT_BRANCH)
+  }
+
+  /// All registries in search order: by ascending priority, then by name. The built-in
+  /// registry (online url or `--local` path) is searched last unless the manifest
+  /// declares its own registry named `default`.
+  pub fn registries(&self) -> Vec<RegistrySpec>
+  {
+    let mut specs = self.registries.clone();
+    if !specs.iter().any(|spec| spec.name == DEFAULT_REGISTRY_NAME) {
+      specs.push(RegistrySpec {
+        name: DEFAULT_REGISTRY_NAME.to_string(),
+        kind: match &self.offline_registry_url {
+          Some(path) => SourceKind::Local { path: path.clone() },
+          None => SourceKind::remote(self.online_registry_url.as_str(), self.registry_ref.clone())
+        },
+        priority: i32::MAX
+      });
+    }
+    specs.sort_by(|a, b| a.priority
+      .cmp(&b.priority)
+      .then_with(|| a.name.cmp(&b.name))
+    );
+    specs
+  }
+
+  pub fn is_offline(&self) -> bool
+  {
+    self.offline_registry_url.is_some()
+  }
+
+  fn user_config_path() -> Option<PathBuf>
+  {
+    match std::env::var(USER_CONFIG_ENV) {
+      Ok(path) if !path.is_empty() => Some(PathBuf::from(path)),
+      _ => dirs::config_dir().map(|dir| dir.join(FDM_NAME).join(USER_CONFIG_NAME))
+    }
+  }
+
+  /// Reads the user config file, if there is one.
+  pub fn load_user_config(&mut self) -> Result<&Self, Error>
+  {
+    let Some(path) = Self::user_config_path().filter(|path| path.is_file()) else {
+      return Ok(self);
+    };
+    log!("loading user config from: {}", path.display());
+    let config: UserConfigPT = toml::from_str(std::fs::read_to_string(&path)?.as_str())
+      .with_context(|| format!("failed to parse user config {}", path.display()))?;
+    for (from, to) in &config.mirrors {
+      Url::parse(to).with_context(|| format!("invalid mirror url for {}: {}", from, to))?;
+    }
+    self.mirrors = config.mirrors;
+    self.http_file = config.http;
+    Ok(self)
+  }
+
+  /// Http client settings: command line first, then environment, then the user config file.
+  pub fn client_config(&self) -> Result<ClientConfig, Error>
+  {
+    let settings = self.http_args
+      .clone()
+      .or(HttpSettingsPT::from_env()?)
+      .or(self.http_file.clone());
+    let defaults = ClientConfig::default();
+    Ok(ClientConfig
+    {
+      user_agent: settings.user_agent
+        .as_deref()
+        .map(UserAgent::from)
+        .or(defaults.user_agent),
+      redirect: defaults.redirect,
+      connect_timeout: settings.connect_timeout
+        .map(Duration::from_secs)
+        .unwrap_or(defaults.connect_timeout),
+      read_timeout: settings.read_timeout
+        .map(Duration::from_secs)
+        .or(defaults.read_timeout),
+      proxy: settings.proxy,
+      no_proxy: settings.no_proxy,
+      ca_bundle: settings.ca_bundle
+    })
+  }
+
+  /// Applies the longest matching mirror prefix to an artifact url.
+  pub fn rewrite_url(&self, url: &Url) -> Url
+  {
+    self.mirrors
+      .iter()
+      .filter(|(from, _)| url.as_str().starts_with(from.as_str()))
+      .max_by_key(|(from, _)| from.len())
+      .and_then(|(from, to)| Url::parse(format!("{}{}", to, &url.as_str()[from.len()..]).as_str()).ok())
+      .unwrap_or_else(|| url.clone())
+  }
+
+  /// Applies registry settings from the manifest. Command line arguments take precedence.
+  pub fn load_manifest(&mut self, manifest: &crate::manifest::Manifest) -> &Self
+  {
+    if self.registry_ref.is_none() {
+      self.registry_ref = manifest.registry_ref.clone();
+    }
+    self.registries = manifest.registries.clone();
+    self
+  }
+
   pub fn load_args(&mut self, args: &crate::cli::args::Args) -> &Self
   {
     if args.offline {
@@ -65,6 +292,36 @@ impl Config
     if args.registry.is_some() {
       self.online_registry_url = args.registry.as_ref().unwrap().to_string();
     }
+    if let Some(reference) = &args.registry_ref {
+      self.registry_ref = Some(reference.to_string());
+    }
+    if let Some(jobs) = args.jobs {
+      if jobs == 0 {
+        fatal_error!("argument --jobs must be at least 1!");
+        std::process::exit(1);
+      }
+      self.jobs = jobs;
+    }
+    self.force = args.force;
+    self.http_args = HttpSettingsPT
+    {
+      proxy: args.proxy.clone(),
+      no_proxy: args.no_proxy.clone(),
+      ca_bundle: args.ca_bundle.clone(),
+      connect_timeout: args.connect_timeout,
+      read_timeout: args.read_timeout,
+      user_agent: args.user_agent.clone()
+    };
+    if let Some(cache_dir) = &args.cache_dir {
+      self.cache_dir = Some(cache_dir.to_string());
+    }
+    if let Some(retries) = args.retries {
+      if retries == 0 {
+        fatal_error!("argument --retries must be at least 1!");
+        std::process::exit(1);
+      }
+      self.retries = retries;
+    }
     if args.operating_system.is_some() && args.architecture.is_none()
       || args.operating_system.is_none() && args.architecture.is_some() {
       fatal_error!("argument --operating-system requires --architecture to be set! (or vice versa)");
@@ -1,8 +1,11 @@
 pub const FDM_NAME: &str = env!("CARGO_PKG_NAME");
 pub const FDM_VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const FDM_MANIFEST_FILENAME: &str = "fdm.toml";
+pub const FDM_LOCKFILE_NAME: &str = "fdm.lock";
+pub const FDM_INSTALL_STATE_NAME: &str = "installed.toml";
 pub const FDM_DIRECTORY_NAME: &str = "fdm";
 pub const FDM_REGISTRY_NAME: &str = "reg";
 pub const FDM_CACHE_NAME: &str = "cache";
 pub const FDM_PACK_NAME: &str = "pack";
-pub const FDM_LIBS_NAME: &str = "libs";
\ No newline at end of file
+pub const FDM_LIBS_NAME: &str = "libs";
+pub const FDM_CMAKE_MINIMUM_VERSION: &str = "3.11";
\ No newline at end of file
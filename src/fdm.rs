@@ -1,22 +1,41 @@
+use std::collections::HashMap;
 use std::path::Path;
 use anyhow::Error;
-use crate::cmake::{CMakeFile, FileGlobMode, FileRelativeMode, ListMode};
-use crate::config::wd;
-use crate::consts::{FDM_DIRECTORY_NAME, FDM_LIBS_NAME, FDM_PACK_NAME};
+use colored::Colorize;
+use crate::cmake::{CMakeFile, ImportedTarget};
+use crate::config::{CONFIG, wd};
+use crate::consts::{FDM_CMAKE_MINIMUM_VERSION, FDM_DIRECTORY_NAME, FDM_LIBS_NAME, FDM_PACK_NAME};
 use crate::registry::REGISTRY;
 use crate::cmake::CMakeCommand::*;
-use crate::log;
+use crate::{log, warn};
+use crate::manifest::InstallState;
+use crate::types::Distribution;
+use crate::types::dependencies::ResolvedDependency;
 
 pub fn run() -> Result<(), Error>
 {
+  let manifest = crate::manifest::Manifest::seek()?;
+  CONFIG
+    .lock()
+    .unwrap()
+    .load_user_config()?;
+  CONFIG
+    .lock()
+    .unwrap()
+    .load_manifest(&manifest);
   REGISTRY
     .lock()
     .unwrap()
-    .init_registry()?
+    .init_registry(manifest.dependencies.as_ref().unwrap_or(&HashMap::new()))?
     .dump_to_cli()?;
-  let _downloaded_names = crate::manifest::Manifest::seek()?
+  let mut resolved = manifest
     .dump_to_cli()?
     .download_dependencies()?;
+  // a package is always deeper than its dependents, so this puts dependencies first
+  resolved.sort_by(|a, b| b.depth
+    .cmp(&a.depth)
+    .then_with(|| a.name.cmp(&b.name))
+  );
   let cmake_path_root = Path::new(&wd()?)
     .join(FDM_DIRECTORY_NAME)
     .join("CMakeLists.txt")
@@ -30,57 +49,132 @@ pub fn run() -> Result<(), Error>
     .into_os_string()
     .into_string()
     .expect("os string should be convertible to string");
-  let cmake_path = Path::new(&wd()?)
+  let libs_path = Path::new(&wd()?)
     .join(FDM_DIRECTORY_NAME)
     .join(FDM_PACK_NAME)
-    .join(FDM_LIBS_NAME)
+    .join(FDM_LIBS_NAME);
+  let cmake_path = libs_path
     .join("CMakeLists.txt")
     .into_os_string()
     .into_string()
     .expect("os string should be convertible to string");
+  let targets = imported_targets(&resolved, &libs_path)?;
   println!();
-  log!("creating new root cmake file...");
-  CMakeFile::new(&cmake_path_root)?
+  log!("generating cmake files...");
+  let root_changed = CMakeFile::from_file(&cmake_path_root)?
     .command(add_subdirectory(FDM_PACK_NAME.to_string()))?
     .commit()?;
-  log!("creating new root cmake file.....\tOK");
-  log!("creating internal cmake...");
-  CMakeFile::new(&cmake_internal_path)?
+  let internal_changed = CMakeFile::from_file(&cmake_internal_path)?
     .command(add_subdirectory(FDM_LIBS_NAME.to_string()))?
     .commit()?;
-  log!("creating internal cmake..........\tOK");
-  log!("creating cmake collection file...");
-  CMakeFile::new(&cmake_path)?
-    .command(macro_start("SUBDIRLIST".to_string(), "result curdir".to_string()))?
-    .command(file_glob(
-      FileGlobMode::Glob,
-      "children".to_string(),
-      FileRelativeMode::Relative,
-      "${curdir}".to_string(),
-      "${curdir}/*".to_string()
-    ))?
-    .command(set("dirlist".to_string(), r#""""#.to_string()))?
-    .command(foreach_start("child".to_string(), "${children}".to_string()))?
-    .command(if_start("IS_DIRECTORY ${curdir}/${child}".to_string()))?
-    .command(list(
-      ListMode::Append,
-      "dirlist".to_string(),
-      "${child}".to_string()
-    ))?
-    .command(if_end)?
-    .command(foreach_end)?
-    .command(set("${result}".to_string(), "${dirlist}".to_string()))?
-    .command(macro_end)?
-    .command(any("\n".to_string()))?
-    .command(any("SUBDIRLIST(SUBDIRS ${CMAKE_CURRENT_SOURCE_DIR})".to_string()))?
-    .command(foreach_start(
-      "subdir".to_string(),
-      "${SUBDIRS}".to_string()
-    ))?
-    .command(add_subdirectory("${subdir}".to_string()))?
-    .command(foreach_end)?
-    .command(any("\n".to_string()))?
-    .commit()?;
-  log!("creating cmake collection file...\tOK");
+  let mut collection = CMakeFile::from_file(&cmake_path)?;
+  collection
+    .command(cmake_minimum_required(FDM_CMAKE_MINIMUM_VERSION.to_string()))?
+    .command(any(String::new()))?;
+  for target in &targets {
+    log!("\t🔹 {} ({})", ImportedTarget::target_name(&target.name).cyan().bold(), target.distribution);
+    for command in target.commands() {
+      collection.command(command)?;
+    }
+    collection.command(any(String::new()))?;
+  }
+  // prebuilt packages come in as imported targets, packages from sources bring their own build
+  for dependency in resolved
+    .iter()
+    .filter(|dependency| !matches!(dependency.distribution, Distribution::Static | Distribution::Shared))
+  {
+    if !libs_path.join(&dependency.name).join("CMakeLists.txt").is_file() {
+      warn!("{} has no CMakeLists.txt, it is not added to the build", dependency.name);
+      continue;
+    }
+    collection.command(add_subdirectory(dependency.name.clone()))?;
+  }
+  let collection_changed = collection.commit()?;
+  let changed = [
+    (cmake_path_root, root_changed),
+    (cmake_internal_path, internal_changed),
+    (cmake_path, collection_changed)
+  ]
+    .into_iter()
+    .filter(|(_, changed)| *changed)
+    .map(|(path, _)| path)
+    .collect::<Vec<String>>();
+  if changed.is_empty() {
+    log!("cmake files are up to date");
+  }
+  else {
+    log!("updated cmake files:");
+    for path in &changed {
+      log!("\t✏ {}", Path::new(path)
+        .strip_prefix(wd()?)
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|_| path.clone())
+        .yellow()
+      );
+    }
+  }
+  Ok(())
+}
+
+/// Shows what the registries of the current project know about a package.
+pub fn info(name: &str) -> Result<(), Error>
+{
+  let manifest = crate::manifest::Manifest::seek()?;
+  CONFIG
+    .lock()
+    .unwrap()
+    .load_user_config()?;
+  CONFIG
+    .lock()
+    .unwrap()
+    .load_manifest(&manifest);
+  // the project's own requirement says which registry to look in
+  let root = manifest.dependencies
+    .as_ref()
+    .and_then(|dependencies| dependencies.get(name))
+    .cloned()
+    .unwrap_or_default();
+  REGISTRY
+    .lock()
+    .unwrap()
+    .init_registry(&HashMap::from([(name.to_string(), root)]))?
+    .dump_package(name)?;
+  if let Some(installed) = InstallState::load()?.packages.get(name) {
+    println!();
+    log!("installed: {} {}/{}",
+      installed.version.bold(),
+      installed.distribution,
+      installed.platform
+    );
+  }
   Ok(())
-}
\ No newline at end of file
+}
+
+/// Imported targets of the prebuilt packages, each linking the prebuilt packages it depends on.
+fn imported_targets(resolved: &[ResolvedDependency], libs_path: &Path) -> Result<Vec<ImportedTarget>, Error>
+{
+  let registry = REGISTRY
+    .lock()
+    .unwrap();
+  let prebuilt = resolved
+    .iter()
+    .filter(|dependency| matches!(dependency.distribution, Distribution::Static | Distribution::Shared))
+    .map(|dependency| dependency.name.as_str())
+    .collect::<Vec<&str>>();
+  let mut targets = Vec::new();
+  for dependency in resolved {
+    let mut dependencies = registry
+      .get_indirect_dependencies(&dependency.registry, &dependency.name, &dependency.version)?
+      .into_keys()
+      .map(|name| match prebuilt.contains(&name.as_str()) {
+        true => ImportedTarget::target_name(&name),
+        // added with add_subdirectory, its build defines a target named after the package
+        false => name
+      })
+      .collect::<Vec<String>>();
+    dependencies.sort();
+    let link = registry.get_link_info(&dependency.registry, &dependency.name, &dependency.version, &dependency.distribution)?;
+    targets.extend(ImportedTarget::discover(dependency, &link, libs_path, dependencies)?);
+  }
+  Ok(targets)
+}
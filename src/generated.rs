@@ -0,0 +1,57 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use anyhow::{Context, Error};
+
+/// Writes a generated file unless it already holds exactly `content`, so unchanged files
+/// keep their mtime. The content goes to a temporary file next to `path` that is renamed
+/// over it, so nobody ever reads a half-written file. Returns whether the file changed.
+pub fn write_if_changed(path: &Path, content: &str) -> Result<bool, Error>
+{
+  if std::fs::read(path).is_ok_and(|existing| existing == content.as_bytes()) {
+    return Ok(false);
+  }
+  if let Some(parent) = path.parent() {
+    std::fs::create_dir_all(parent)?;
+  }
+  let file_name = path
+    .file_name()
+    .with_context(|| format!("invalid file path {}", path.display()))?
+    .to_string_lossy();
+  let temp = path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()));
+  let written = File::create(&temp)
+    .and_then(|mut file| {
+      file.write_all(content.as_bytes())?;
+      file.sync_all()
+    })
+    .and_then(|_| std::fs::rename(&temp, path));
+  if let Err(e) = written {
+    let _ = std::fs::remove_file(&temp);
+    return Err(Error::from(e).context(format!("failed to write {}", path.display())));
+  }
+  Ok(true)
+}
+
+#[cfg(test)]
+mod tests
+{
+  use std::time::{Duration, SystemTime};
+  use super::*;
+
+  #[test]
+  fn unchanged_content_is_not_rewritten()
+  {
+    let dir = std::env::temp_dir().join(format!("fdm-generated-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let path = dir.join("nested").join("file.txt");
+    assert!(write_if_changed(&path, "a\n").unwrap());
+    let old = SystemTime::now() - Duration::from_secs(3600);
+    File::options().write(true).open(&path).unwrap().set_modified(old).unwrap();
+    assert!(!write_if_changed(&path, "a\n").unwrap());
+    assert_eq!(std::fs::metadata(&path).unwrap().modified().unwrap(), old);
+    assert!(write_if_changed(&path, "b\n").unwrap());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "b\n");
+    assert_eq!(std::fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -1,6 +1,8 @@
 mod cli;
 mod consts;
 mod config;
+mod cache;
+mod generated;
 mod fdm;
 mod types;
 mod registry;
@@ -43,14 +45,19 @@ fn main()
     .unwrap()
     .load_args(&args)
     .dump_to_cli();
-  match args.load {
-    true => fdm::run()
+  let result = match (args.load, &args.info) {
+    (true, _) => Some(fdm::run()),
+    (false, Some(name)) => Some(fdm::info(name)),
+    (false, None) => None
+  };
+  match result {
+    Some(result) => result
       .map_err(|err| {
-        fatal_error!("{}", err);
+        fatal_error!("{:#}", err);
         std::process::exit(1);
       })
       .unwrap(),
-    false => {
+    None => {
       fatal_error!("no subcommand specified. see --help for more information");
       std::process::exit(1);
     }
@@ -1,5 +1,5 @@
 use serde::Deserialize;
-use crate::types::{Distribution, Version};
+use crate::types::{Distribution, VersionReq};
 use crate::types::dependencies::Dependency;
 
 #[derive(Debug, Deserialize)]
@@ -7,6 +7,7 @@ pub struct DependencyPT
 {
   pub version: String,
   pub distribution: String,
+  pub registry: Option<String>
 }
 
 impl TryFrom<DependencyPT> for Dependency
@@ -16,9 +17,10 @@ impl TryFrom<DependencyPT> for Dependency
   fn try_from(value: DependencyPT) -> Result<Self, Self::Error>
   {
     Ok(Self {
-      version: Version::try_from(value.version.as_str())?,
+      version: VersionReq::try_from(value.version.as_str())?,
       distribution: Distribution::from(value.distribution.as_str()),
-      arch: None
+      arch: None,
+      registry: value.registry
     })
   }
 }
\ No newline at end of file
@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Error};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use crate::config::wd;
+use crate::consts::{FDM_DIRECTORY_NAME, FDM_INSTALL_STATE_NAME, FDM_LIBS_NAME, FDM_PACK_NAME};
+use crate::generated::write_if_changed;
+use crate::warn;
+use crate::types::dependencies::ResolvedDependency;
+
+const INSTALL_STATE_HEADER: &str = "# installed packages, maintained by fdm. do not edit it manually.\n\n";
+
+/// What is currently unpacked into `fdm/pack/libs`, so unchanged packages can be skipped.
+#[derive(Debug, Default)]
+pub struct InstallState
+{
+  pub packages: BTreeMap<String, InstalledPackagePT>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstalledPackagePT
+{
+  pub version: String,
+  pub distribution: String,
+  pub platform: String,
+  pub url: String,
+  pub checksum: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub strip: Option<usize>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub subdir: Option<String>
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct InstallStatePT
+{
+  #[serde(default, rename = "package")]
+  pub packages: BTreeMap<String, InstalledPackagePT>
+}
+
+impl From<&ResolvedDependency> for InstalledPackagePT
+{
+  fn from(value: &ResolvedDependency) -> Self
+  {
+    Self
+    {
+      version: value.version.to_string(),
+      distribution: value.distribution.to_string(),
+      platform: value.arch.to_string(),
+      url: value.url.to_string(),
+      checksum: value.checksum.clone(),
+      strip: value.layout.strip,
+      subdir: value.layout.subdir.clone()
+    }
+  }
+}
+
+impl InstallState
+{
+  pub fn path() -> Result<PathBuf, Error>
+  {
+    Ok(Path::new(&wd()?)
+      .join(FDM_DIRECTORY_NAME)
+      .join(FDM_INSTALL_STATE_NAME))
+  }
+
+  pub fn package_path(name: &str) -> Result<PathBuf, Error>
+  {
+    Ok(Path::new(&wd()?)
+      .join(FDM_DIRECTORY_NAME)
+      .join(FDM_PACK_NAME)
+      .join(FDM_LIBS_NAME)
+      .join(name))
+  }
+
+  /// Reads the state file. A missing or unreadable one just means nothing is known to be installed.
+  pub fn load() -> Result<Self, Error>
+  {
+    let path = Self::path()?;
+    if !path.exists() {
+      return Ok(Self::default());
+    }
+    match toml::from_str::<InstallStatePT>(std::fs::read_to_string(&path)?.as_str()) {
+      Ok(state) => Ok(Self { packages: state.packages }),
+      Err(e) => {
+        warn!("ignoring unreadable install state {}: {}", path.display(), e);
+        Ok(Self::default())
+      }
+    }
+  }
+
+  /// True if exactly this package is unpacked. Without a known checksum the url has to match.
+  pub fn is_installed(&self, dependency: &ResolvedDependency) -> bool
+  {
+    let wanted = InstalledPackagePT::from(dependency);
+    let Some(installed) = self.packages.get(&dependency.name) else {
+      return false;
+    };
+    let same_content = match &wanted.checksum {
+      Some(_) => installed.checksum == wanted.checksum,
+      None => installed.url == wanted.url
+    };
+    same_content
+      && installed.version == wanted.version
+      && installed.distribution == wanted.distribution
+      && installed.platform == wanted.platform
+      && installed.strip == wanted.strip
+      && installed.subdir == wanted.subdir
+      && Self::package_path(&dependency.name).is_ok_and(|path| path.is_dir())
+  }
+
+  pub fn record(&mut self, dependency: &ResolvedDependency)
+  {
+    self.packages.insert(dependency.name.clone(), InstalledPackagePT::from(dependency));
+  }
+
+  /// Deletes installed packages that are not in `keep` any more.
+  pub fn prune(&mut self, keep: &[ResolvedDependency]) -> Result<Vec<String>, Error>
+  {
+    let stale = self.packages
+      .keys()
+      .filter(|name| !keep.iter().any(|dependency| &dependency.name == *name))
+      .cloned()
+      .collect::<Vec<String>>();
+    for name in &stale {
+      let path = Self::package_path(name)?;
+      if path.exists() {
+        std::fs::remove_dir_all(&path)
+          .with_context(|| format!("failed to remove {}", path.display()))?;
+      }
+      self.packages.remove(name);
+    }
+    Ok(stale)
+  }
+
+  pub fn commit(&self) -> Result<bool, Error>
+  {
+    let state = InstallStatePT
+    {
+      packages: self.packages.clone()
+    };
+    write_if_changed(&Self::path()?, format!("{}{}", INSTALL_STATE_HEADER, toml::to_string(&state)?).as_str())
+  }
+}
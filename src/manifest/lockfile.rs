@@ -0,0 +1,182 @@
+use std::path::Path;
+use anyhow::{Context, ensure, Error};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use url::Url;
+use crate::config::wd;
+use crate::consts::FDM_LOCKFILE_NAME;
+use crate::generated::write_if_changed;
+use crate::log;
+use crate::registry::DEFAULT_REGISTRY_NAME;
+use crate::types::{Distribution, ExtractLayout, PlatformArch, Version};
+use crate::types::dependencies::ResolvedDependency;
+
+const LOCKFILE_FORMAT_VERSION: u32 = 1;
+const LOCKFILE_HEADER: &str = "# this file is generated by fdm. do not edit it manually.\n\n";
+
+#[derive(Debug, Default)]
+pub struct Lockfile
+{
+  pub manifest_hash: String,
+  pub packages: Vec<ResolvedDependency>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LockfilePT
+{
+  pub version: u32,
+  pub manifest: String,
+  #[serde(default, rename = "package")]
+  pub packages: Vec<LockedPackagePT>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LockedPackagePT
+{
+  pub name: String,
+  pub version: String,
+  pub distribution: String,
+  pub platform: String,
+  pub url: String,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub mirrors: Vec<String>,
+  pub checksum: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub strip: Option<usize>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub subdir: Option<String>,
+  #[serde(default)]
+  pub depth: usize,
+  #[serde(default = "default_registry")]
+  pub registry: String
+}
+
+fn default_registry() -> String
+{
+  DEFAULT_REGISTRY_NAME.to_string()
+}
+
+impl From<&ResolvedDependency> for LockedPackagePT
+{
+  fn from(value: &ResolvedDependency) -> Self
+  {
+    Self
+    {
+      name: value.name.clone(),
+      version: value.version.to_string(),
+      distribution: value.distribution.to_string(),
+      platform: value.arch.to_string(),
+      url: value.url.to_string(),
+      mirrors: value.mirrors
+        .iter()
+        .map(|url| url.to_string())
+        .collect(),
+      checksum: value.checksum.clone(),
+      strip: value.layout.strip,
+      subdir: value.layout.subdir.clone(),
+      depth: value.depth,
+      registry: value.registry.clone()
+    }
+  }
+}
+
+impl TryFrom<LockedPackagePT> for ResolvedDependency
+{
+  type Error = anyhow::Error;
+
+  fn try_from(value: LockedPackagePT) -> Result<Self, Self::Error>
+  {
+    Ok(Self
+    {
+      version: Version::try_from(value.version.as_str())
+        .with_context(|| format!("invalid version of locked package {}", value.name))?,
+      distribution: Distribution::from(value.distribution.as_str()),
+      arch: PlatformArch::from(value.platform.as_str()),
+      url: Url::parse(value.url.as_str())
+        .with_context(|| format!("invalid url of locked package {}", value.name))?,
+      mirrors: value.mirrors
+        .iter()
+        .map(|url| Url::parse(url.as_str()))
+        .collect::<Result<Vec<Url>, url::ParseError>>()
+        .with_context(|| format!("invalid mirror url of locked package {}", value.name))?,
+      checksum: value.checksum,
+      layout: ExtractLayout::new(value.strip, value.subdir)
+        .with_context(|| format!("invalid layout of locked package {}", value.name))?,
+      depth: value.depth,
+      registry: value.registry,
+      name: value.name
+    })
+  }
+}
+
+impl Lockfile
+{
+  pub fn new(manifest_hash: &str, packages: Vec<ResolvedDependency>) -> Self
+  {
+    let mut packages = packages;
+    packages.sort_by(|a, b| a.depth
+      .cmp(&b.depth)
+      .then_with(|| a.name.cmp(&b.name))
+    );
+    Self
+    {
+      manifest_hash: manifest_hash.to_string(),
+      packages
+    }
+  }
+
+  pub fn path() -> Result<String, Error>
+  {
+    Ok(Path::new(&wd()?)
+      .join(FDM_LOCKFILE_NAME)
+      .into_os_string()
+      .into_string()
+      .expect("os string should be convertible to string"))
+  }
+
+  /// Reads `fdm.lock` from the current directory, if there is one.
+  pub fn seek() -> Result<Option<Self>, Error>
+  {
+    let path = Self::path()?;
+    if !Path::new(&path).exists() {
+      return Ok(None);
+    }
+    let lock: LockfilePT = toml::from_str(std::fs::read_to_string(&path)?.as_str())
+      .context("failed to parse lockfile")?;
+    ensure!(lock.version == LOCKFILE_FORMAT_VERSION, "unsupported lockfile version {}", lock.version);
+    Ok(Some(Self
+    {
+      manifest_hash: lock.manifest,
+      packages: lock.packages
+        .into_iter()
+        .map(ResolvedDependency::try_from)
+        .collect::<Result<Vec<ResolvedDependency>, Error>>()?
+    }))
+  }
+
+  pub fn commit(&self) -> Result<bool, Error>
+  {
+    let lock = LockfilePT
+    {
+      version: LOCKFILE_FORMAT_VERSION,
+      manifest: self.manifest_hash.clone(),
+      packages: self.packages
+        .iter()
+        .map(LockedPackagePT::from)
+        .collect()
+    };
+    let content = format!("{}{}", LOCKFILE_HEADER, toml::to_string(&lock)?);
+    let changed = write_if_changed(Path::new(&Self::path()?), content.as_str())?;
+    if changed {
+      log!("written lockfile with {} packages", self.packages.len().to_string().bold());
+    } else {
+      log!("lockfile is up to date");
+    }
+    Ok(changed)
+  }
+
+  pub fn is_up_to_date(&self, manifest_hash: &str) -> bool
+  {
+    self.manifest_hash == manifest_hash
+  }
+}
@@ -1,31 +1,42 @@
 use std::collections::HashMap;
 use std::path::Path;
-use anyhow::{ensure, Error};
+use anyhow::{ensure, Context, Error};
+use futures::StreamExt;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use colored::Colorize;
 use serde::Deserialize;
-use crate::config::wd;
+use sha2::{Digest, Sha256};
+use crate::config::{CONFIG, wd};
 use crate::consts::FDM_MANIFEST_FILENAME;
-use crate::log;
+use crate::{log, warn};
 use crate::manifest::{
   DependencyPT,
+  Lockfile,
+  InstallState,
   Package,
-  PackagePT
+  PackagePT,
+  RegistryPT,
+  RegistrySourcePT
 };
-use crate::registry::REGISTRY;
-use crate::types::dependencies::Dependency;
+use crate::registry::{RegistrySpec, Resolver, REGISTRY};
+use crate::types::dependencies::{Dependency, ResolvedDependency};
 
 #[derive(Debug)]
 pub struct Manifest
 {
   pub package: Package,
-  pub dependencies: Option<HashMap<String, Dependency>>
+  pub dependencies: Option<HashMap<String, Dependency>>,
+  pub registry_ref: Option<String>,
+  pub registries: Vec<RegistrySpec>
 }
 
 #[derive(Debug, Deserialize)]
 struct ManifestPT
 {
   pub package: PackagePT,
-  pub dependencies: Option<HashMap<String, DependencyPT>>
+  pub dependencies: Option<HashMap<String, DependencyPT>>,
+  pub registry: Option<RegistryPT>,
+  pub registries: Option<HashMap<String, RegistrySourcePT>>
 }
 
 impl Default for Manifest
@@ -35,7 +46,9 @@ impl Default for Manifest
     Self
     {
       package: Package::default(),
-      dependencies: None
+      dependencies: None,
+      registry_ref: None,
+      registries: Vec::new()
     }
   }
 }
@@ -57,7 +70,14 @@ impl TryFrom<ManifestPT> for Manifest
           })
           .collect::<Result<HashMap<String, Dependency>, Error>>()
         )
-        .transpose()?
+        .transpose()?,
+      registry_ref: value.registry
+        .and_then(|registry| registry.reference),
+      registries: value.registries
+        .unwrap_or_default()
+        .into_iter()
+        .map(RegistrySpec::try_from)
+        .collect::<Result<Vec<RegistrySpec>, Error>>()?
     })
   }
 }
@@ -114,36 +134,224 @@ impl Manifest
     Ok(self)
   }
 
+  /// Hash of the dependency section and the target platform, used to detect manifest
+  /// or platform changes against the lockfile.
+  pub fn dependencies_hash(&self) -> String
+  {
+    let mut lines = self.dependencies
+      .as_ref()
+      .map(|deps| deps
+        .iter()
+        .map(|(name, dep)| format!("{} {} {}{}\n",
+          name,
+          dep.version,
+          dep.distribution,
+          dep.registry
+            .as_ref()
+            .map(|registry| format!(" {}", registry))
+            .unwrap_or_default()
+        ))
+        .collect::<Vec<String>>()
+      )
+      .unwrap_or_default();
+    lines.sort();
+    // dependencies without an explicit arch resolve to artifacts of the target platform
+    lines.push(format!("platform {}\n", CONFIG.lock().unwrap().platform));
+    format!("{:x}", Sha256::digest(lines.concat().as_bytes()))
+  }
+
   #[tokio::main]
-  pub async fn download_dependencies(&self) -> Result<Vec<String>, Error>
+  /// Installs every resolved dependency and returns them in lockfile order.
+  pub async fn download_dependencies(&self) -> Result<Vec<ResolvedDependency>, Error>
   {
     println!();
-    let mut names = Vec::new();
-    if self.dependencies.is_none() {
+    // without dependencies everything installed before is pruned like any removed dependency
+    let empty = self.dependencies.as_ref().is_none_or(HashMap::is_empty);
+    if empty {
       log!("no dependencies for package: {}", self.package.name.to_string().magenta().bold());
-      return Ok(names);
     } else {
       log!("downloading dependencies for package: {}", self.package.name.to_string().magenta().bold());
     }
 
-    let deps = self.dependencies.as_ref().unwrap();
-    let mut dependency_tree = HashMap::new();
-    dependency_tree.extend(deps.clone());
-    {
-      // mutex scope
-      let reg = REGISTRY
-          .lock()
-          .unwrap();
-      for (name, dep) in deps {
-        let t = reg.get_recursively(name, dep)?;
-        dependency_tree.extend(t);
+    let hash = self.dependencies_hash();
+    // nothing to pin: the old lockfile is not read, an empty one replaces it below
+    let locked = match empty {
+      true => None,
+      false => Lockfile::seek()?
+    };
+    let resolved = match locked {
+      Some(lock) if lock.is_up_to_date(&hash) => {
+        log!("using locked dependency versions from lockfile");
+        lock.packages
+      },
+      Some(_) => {
+        warn!("manifest or target platform has changed since lockfile was written, resolving dependencies again");
+        self.resolve_dependencies()?
+      },
+      None => self.resolve_dependencies()?
+    };
+    log!("resolved {} packages:", resolved.len().to_string().bold());
+    for dependency in &resolved {
+      log!("\t{}🔹 {} {} from {} (depth {})",
+        "  ".repeat(dependency.depth.saturating_sub(1)),
+        dependency.name.to_string().cyan().bold(),
+        dependency.version.to_string().bold(),
+        dependency.registry.as_str().yellow(),
+        dependency.depth
+      );
+    }
+    let (jobs, force) = {
+      let cfg = CONFIG
+        .lock()
+        .unwrap();
+      (cfg.jobs, cfg.force)
+    };
+    // the state is loaded even when forced, so removed packages are still pruned
+    let mut state = InstallState::load()?;
+    for name in state.prune(&resolved)? {
+      log!("removed {} (no longer a dependency)", name.as_str().bold());
+    }
+    if force {
+      log!("{}", "reinstalling all dependencies".yellow());
+    }
+    let (mut installed, pending): (Vec<ResolvedDependency>, Vec<ResolvedDependency>) = resolved
+      .into_iter()
+      .partition(|dependency| !force && state.is_installed(dependency));
+    for dependency in &mut installed {
+      log!("{} {} is up to date", dependency.name.as_str().cyan().bold(), dependency.version.to_string().bold());
+      dependency.checksum = state.packages[&dependency.name].checksum.clone();
+    }
+    for dependency in &pending {
+      self.check_checksum_policy(dependency)?;
+    }
+    // the client is reference counted, so every download gets its own handle and the
+    // registry stays unlocked while they run
+    let client = REGISTRY
+      .lock()
+      .unwrap()
+      .client
+      .clone();
+    let progress = MultiProgress::with_draw_target(ProgressDrawTarget::stdout_with_hz(5));
+    let total = progress.add(ProgressBar::new(pending.len() as u64)
+      .with_style(ProgressStyle::default_bar()
+        .template("{wide_msg} {elapsed_precise:8} [{bar:20.green/white}] {pos}/{len} packages")
+        .expect("template should be valid")
+        .progress_chars("█░░")
+      ));
+    total.set_message(format!("downloading with {} jobs...", jobs));
+    let results = futures::stream::iter(pending)
+      .map(|mut dependency| {
+        let client = &client;
+        let progress = &progress;
+        let total = &total;
+        async move {
+          let checksum = dependency
+            .download_from_registry(client, progress)
+            .await
+            .with_context(|| format!("failed to install {}", dependency))?;
+          dependency.checksum = Some(checksum);
+          total.inc(1);
+          Ok::<ResolvedDependency, Error>(dependency)
+        }
+      })
+      .buffered(jobs)
+      .collect::<Vec<Result<ResolvedDependency, Error>>>()
+      .await;
+    // whatever did get installed is remembered even if another package failed
+    let mut failure = None;
+    for result in results {
+      match result {
+        Ok(dependency) => {
+          state.record(&dependency);
+          installed.push(dependency);
+        },
+        Err(e) => {
+          failure.get_or_insert(e);
+        }
       }
     }
-    for dependency in dependency_tree {
-      dependency.1.download_from_registry(dependency.0.as_str())
-        .await?;
-      names.push(dependency.0);
+    state.commit()?;
+    if let Some(e) = failure {
+      total.abandon_with_message("some dependencies failed to download");
+      return Err(e);
+    }
+    total.finish_with_message("all dependencies downloaded!");
+    let lockfile = Lockfile::new(&hash, installed);
+    lockfile.commit()?;
+    Ok(lockfile.packages)
+  }
+
+  fn check_checksum_policy(&self, dependency: &ResolvedDependency) -> Result<(), Error>
+  {
+    if dependency.checksum.is_some() {
+      return Ok(());
     }
-    Ok(names)
+    let required = REGISTRY
+      .lock()
+      .unwrap()
+      .source(&dependency.registry)?
+      .requires_checksums();
+    ensure!(!required,
+      "registry {} requires checksums, but {} {} has no sha256 for {}",
+      dependency.registry,
+      dependency.name,
+      dependency.version,
+      dependency.url
+    );
+    warn!("no sha256 checksum for {} {} in registry {}, archive will not be verified",
+      dependency.name,
+      dependency.version,
+      dependency.registry
+    );
+    Ok(())
+  }
+
+  fn resolve_dependencies(&self) -> Result<Vec<ResolvedDependency>, Error>
+  {
+    let Some(dependencies) = &self.dependencies else {
+      return Ok(Vec::new());
+    };
+    let reg = REGISTRY
+      .lock()
+      .unwrap();
+    Resolver::new(&reg, &self.package.name)
+      .resolve(dependencies)
   }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests
+{
+  use crate::types::PlatformArch;
+  use super::*;
+
+  const PACKAGE: &str = "[package]\nname = \"app\"\nversion = \"0.1.0\"\nauthors = [\"x\"]\n";
+
+  fn hash(dependencies: &str) -> String
+  {
+    let toml = format!("{}\n[dependencies]\n{}", PACKAGE, dependencies);
+    Manifest::try_from(Manifest::from_toml_string(&toml).unwrap())
+      .unwrap()
+      .dependencies_hash()
+  }
+
+  #[test]
+  fn lockfile_follows_manifest_and_platform()
+  {
+    let liba = "liba = { version = \"^1.0\", distribution = \"sources\" }\n";
+    let libb = "libb = { version = \"2\", distribution = \"static\" }\n";
+    let lock = Lockfile::new(&hash(&format!("{}{}", liba, libb)), Vec::new());
+    // the order of the manifest does not matter
+    assert!(lock.is_up_to_date(&hash(&format!("{}{}", libb, liba))));
+    assert!(!lock.is_up_to_date(&hash(&format!("{}{}", liba.replace("1.0", "1.1"), libb))));
+    assert!(!lock.is_up_to_date(&hash(&format!("{}{}", liba, libb.replace("static", "shared")))));
+    assert!(!lock.is_up_to_date(&hash(liba)));
+    let platform = CONFIG.lock().unwrap().platform.clone();
+    CONFIG.lock().unwrap().platform = match platform {
+      PlatformArch::WindowsX64 => PlatformArch::LinuxX64,
+      _ => PlatformArch::WindowsX64
+    };
+    let other_platform = hash(&format!("{}{}", liba, libb));
+    CONFIG.lock().unwrap().platform = platform;
+    assert!(!lock.is_up_to_date(&other_platform));
+  }
+}
@@ -1,6 +1,9 @@
 pub mod package;
 pub mod dependencies;
+pub mod registry;
 pub mod manifest;
+pub mod lockfile;
+pub mod install_state;
 
 pub use package::
 {
@@ -8,4 +11,11 @@ pub use package::
   PackagePT
 };
 pub use dependencies::DependencyPT;
-pub use manifest::Manifest;
\ No newline at end of file
+pub use registry::
+{
+  RegistryPT,
+  RegistrySourcePT
+};
+pub use manifest::Manifest;
+pub use lockfile::Lockfile;
+pub use install_state::InstallState;
\ No newline at end of file
@@ -0,0 +1,41 @@
+use anyhow::bail;
+use serde::Deserialize;
+use crate::registry::{RegistrySpec, SourceKind};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RegistryPT
+{
+  #[serde(rename = "ref")]
+  pub reference: Option<String>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RegistrySourcePT
+{
+  pub url: Option<String>,
+  pub path: Option<String>,
+  #[serde(rename = "ref")]
+  pub reference: Option<String>,
+  pub priority: Option<i32>
+}
+
+impl TryFrom<(String, RegistrySourcePT)> for RegistrySpec
+{
+  type Error = anyhow::Error;
+
+  fn try_from(value: (String, RegistrySourcePT)) -> Result<Self, Self::Error>
+  {
+    let (name, source) = value;
+    let kind = match (source.url, source.path) {
+      (Some(url), None) => SourceKind::remote(url.as_str(), source.reference),
+      (None, Some(path)) => SourceKind::Local { path },
+      _ => bail!("registry {} must specify exactly one of `url` or `path`", name)
+    };
+    Ok(Self
+    {
+      name,
+      kind,
+      priority: source.priority.unwrap_or(0)
+    })
+  }
+}
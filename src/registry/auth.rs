@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Error};
+use colored::Colorize;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use url::Url;
+use crate::consts::FDM_NAME;
+use crate::warn;
+
+const CREDENTIALS_ENV: &str = "FDM_CREDENTIALS";
+const CREDENTIALS_NAME: &str = "credentials.toml";
+const TOKEN_ENV_PREFIX: &str = "FDM_TOKEN_";
+
+lazy_static!
+{
+  pub static ref CREDENTIALS: Credentials = Credentials::load();
+}
+
+/// Secret for one host. `Debug` never prints the secret itself.
+#[derive(Clone)]
+pub enum Credential
+{
+  Bearer(String),
+  Basic { username: String, password: Option<String> }
+}
+
+impl std::fmt::Debug for Credential
+{
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+  {
+    match self
+    {
+      Self::Bearer(_) => write!(f, "Bearer(***)"),
+      Self::Basic { username, .. } => write!(f, "Basic({}:***)", username)
+    }
+  }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CredentialPT
+{
+  pub token: Option<String>,
+  pub username: Option<String>,
+  pub password: Option<String>
+}
+
+impl TryFrom<CredentialPT> for Credential
+{
+  type Error = anyhow::Error;
+
+  fn try_from(value: CredentialPT) -> Result<Self, Self::Error>
+  {
+    match (value.token, value.username) {
+      (Some(token), None) => Ok(Self::Bearer(token)),
+      (None, Some(username)) => Ok(Self::Basic { username, password: value.password }),
+      _ => bail!("exactly one of token or username must be set")
+    }
+  }
+}
+
+/// Per-host credentials for artifact and registry downloads. Looked up in order:
+/// `FDM_TOKEN_<HOST>` environment variables, the credentials file
+/// (`~/.config/fdm/credentials.toml` or `$FDM_CREDENTIALS`) and `~/.netrc` (or `$NETRC`).
+#[derive(Debug, Default)]
+pub struct Credentials
+{
+  file: HashMap<String, Credential>,
+  netrc: HashMap<String, Credential>
+}
+
+impl Credentials
+{
+  /// Unreadable sources are skipped with a warning, so a broken file never blocks public downloads.
+  pub fn load() -> Self
+  {
+    let mut credentials = Self::default();
+    if let Some(path) = Self::file_path().filter(|path| path.is_file()) {
+      match Self::read_file(&path) {
+        Ok(file) => credentials.file = file,
+        Err(e) => { warn!("ignoring credentials file {}: {:#}", path.display(), e); }
+      }
+    }
+    if let Some(path) = Self::netrc_path().filter(|path| path.is_file()) {
+      match std::fs::read_to_string(&path) {
+        Ok(content) => credentials.netrc = Self::parse_netrc(content.as_str()),
+        Err(e) => { warn!("ignoring {}: {}", path.display(), e); }
+      }
+    }
+    credentials
+  }
+
+  /// Credential for the host of `url`, if any.
+  pub fn for_url(&self, url: &Url) -> Option<Credential>
+  {
+    let host = url.host_str()?.to_ascii_lowercase();
+    if let Ok(token) = std::env::var(Self::token_env(host.as_str())) {
+      if !token.is_empty() {
+        return Some(Credential::Bearer(token));
+      }
+    }
+    self.file
+      .get(&host)
+      .or_else(|| self.netrc.get(&host))
+      .cloned()
+  }
+
+  /// Adds the credential for the request's host. A redirect to another host drops the
+  /// header again (reqwest strips `Authorization` on cross-host redirects).
+  pub fn authorize(&self, request: reqwest::RequestBuilder, url: &Url) -> reqwest::RequestBuilder
+  {
+    match self.for_url(url) {
+      Some(Credential::Bearer(token)) => request.bearer_auth(token),
+      Some(Credential::Basic { username, password }) => request.basic_auth(username, password),
+      None => request
+    }
+  }
+
+  /// `FDM_TOKEN_ARTIFACTS_EXAMPLE_COM` for `artifacts.example.com`.
+  fn token_env(host: &str) -> String
+  {
+    format!("{}{}", TOKEN_ENV_PREFIX, host
+      .chars()
+      .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+      .collect::<String>()
+    )
+  }
+
+  fn file_path() -> Option<PathBuf>
+  {
+    match std::env::var(CREDENTIALS_ENV) {
+      Ok(path) if !path.is_empty() => Some(PathBuf::from(path)),
+      _ => dirs::config_dir().map(|dir| dir.join(FDM_NAME).join(CREDENTIALS_NAME))
+    }
+  }
+
+  fn netrc_path() -> Option<PathBuf>
+  {
+    match std::env::var("NETRC") {
+      Ok(path) if !path.is_empty() => Some(PathBuf::from(path)),
+      _ => dirs::home_dir().map(|dir| dir.join(if cfg!(windows) { "_netrc" } else { ".netrc" }))
+    }
+  }
+
+  fn read_file(path: &Path) -> Result<HashMap<String, Credential>, Error>
+  {
+    let hosts: HashMap<String, CredentialPT> = toml::from_str(std::fs::read_to_string(path)?.as_str())?;
+    hosts
+      .into_iter()
+      .map(|(host, credential)| Ok((
+        host.to_ascii_lowercase(),
+        Credential::try_from(credential).with_context(|| format!("invalid credentials for {}", host))?
+      )))
+      .collect()
+  }
+
+  /// Reads `machine` entries. The `default` entry is ignored on purpose: credentials
+  /// are only ever sent to the host they were given for.
+  fn parse_netrc(content: &str) -> HashMap<String, Credential>
+  {
+    let mut machines = HashMap::new();
+    // (machine, login, password) of the entry being read; machine is None inside `default`
+    let mut entry: Option<(Option<String>, Option<String>, Option<String>)> = None;
+    let mut finish = |entry: Option<(Option<String>, Option<String>, Option<String>)>| {
+      if let Some((Some(machine), Some(username), password)) = entry {
+        machines
+          .entry(machine)
+          .or_insert(Credential::Basic { username, password });
+      }
+    };
+    let mut lines = content.lines();
+    while let Some(line) = lines.next() {
+      let mut tokens = line.split_whitespace();
+      while let Some(token) = tokens.next() {
+        match token {
+          "machine" => {
+            finish(entry.take());
+            entry = tokens.next().map(|machine| (Some(machine.to_ascii_lowercase()), None, None));
+          },
+          "default" => {
+            finish(entry.take());
+            entry = Some((None, None, None));
+          },
+          "login" => if let (Some(entry), Some(login)) = (entry.as_mut(), tokens.next()) {
+            entry.1 = Some(login.to_string());
+          },
+          "password" => if let (Some(entry), Some(password)) = (entry.as_mut(), tokens.next()) {
+            entry.2 = Some(password.to_string());
+          },
+          "account" => { tokens.next(); },
+          "macdef" => {
+            // macro definitions run until the next empty line
+            finish(entry.take());
+            for line in lines.by_ref() {
+              if line.trim().is_empty() {
+                break;
+              }
+            }
+            break;
+          },
+          _ => {}
+        }
+      }
+    }
+    finish(entry);
+    machines
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  fn basic(credential: Option<Credential>) -> Option<(String, Option<String>)>
+  {
+    match credential? {
+      Credential::Basic { username, password } => Some((username, password)),
+      Credential::Bearer(_) => None
+    }
+  }
+
+  #[test]
+  fn netrc_default_is_never_used()
+  {
+    let netrc = Credentials::parse_netrc("\
+      machine Example.com login first password one\n\
+      default login anybody password secret\n\
+      machine example.com login second password two\n\
+      machine other.com\n  login other\n");
+    assert_eq!(netrc.len(), 2);
+    // the first entry of a machine wins, `default` neither adds a host nor fills one in
+    assert_eq!(basic(netrc.get("example.com").cloned()), Some(("first".to_string(), Some("one".to_string()))));
+    assert_eq!(basic(netrc.get("other.com").cloned()), Some(("other".to_string(), None)));
+    let credentials = Credentials { netrc, ..Credentials::default() };
+    assert!(credentials.for_url(&Url::parse("https://unknown.com/a.tar.gz").unwrap()).is_none());
+    assert!(basic(credentials.for_url(&Url::parse("https://EXAMPLE.com/a.tar.gz").unwrap())).is_some());
+  }
+
+  #[test]
+  fn credentials_file_wins_over_netrc()
+  {
+    let credentials = Credentials
+    {
+      file: HashMap::from([("example.com".to_string(), Credential::Bearer("token".to_string()))]),
+      netrc: Credentials::parse_netrc("machine example.com login user password secret\n")
+    };
+    let credential = credentials.for_url(&Url::parse("https://example.com/a.tar.gz").unwrap());
+    assert!(matches!(&credential, Some(Credential::Bearer(token)) if token == "token"));
+    assert_eq!(format!("{:?}", credential), "Some(Bearer(***))");
+  }
+}
@@ -1,5 +1,6 @@
-use std::time::Duration;
-use anyhow::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{ensure, Context, Error};
+use reqwest::{Certificate, NoProxy, Proxy};
 use reqwest::redirect::Policy;
 
 const USER_AGENTS: [&str; 16] =
@@ -22,10 +23,11 @@ const USER_AGENTS: [&str; 16] =
     "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/110.0",
   ];
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum UserAgent
 {
-  Fixed(String)
+  Fixed(String),
+  Random
 }
 
 impl Default for UserAgent
@@ -36,21 +38,141 @@ impl Default for UserAgent
   }
 }
 
-pub fn make_client(user_agent: &Option<UserAgent>, redirect: bool, timeout: usize)
-                   -> Result<reqwest::Client, Error>
+impl From<&str> for UserAgent
+{
+  fn from(value: &str) -> Self
+  {
+    match value {
+      "random" => Self::Random,
+      _ => Self::Fixed(value.to_string())
+    }
+  }
+}
+
+impl UserAgent
+{
+  pub fn value(&self) -> String
+  {
+    match self {
+      Self::Fixed(s) => s.clone(),
+      Self::Random => {
+        let seed = SystemTime::now()
+          .duration_since(UNIX_EPOCH)
+          .map(|d| d.subsec_nanos() as usize)
+          .unwrap_or(0);
+        USER_AGENTS[seed % USER_AGENTS.len()].to_string()
+      }
+    }
+  }
+}
+
+/// Everything needed to build the http client used for registries and downloads.
+#[derive(Debug, Clone)]
+pub struct ClientConfig
+{
+  pub user_agent: Option<UserAgent>,
+  pub redirect: bool,
+  pub connect_timeout: Duration,
+  /// Longest wait for the next chunk of a response. Enforced by the downloader and the
+  /// sparse registry client, since reqwest only knows a timeout for the whole request.
+  pub read_timeout: Option<Duration>,
+  pub proxy: Option<String>,
+  pub no_proxy: Option<String>,
+  pub ca_bundle: Option<String>
+}
+
+impl Default for ClientConfig
+{
+  fn default() -> Self
+  {
+    Self
+    {
+      user_agent: Some(UserAgent::default()),
+      redirect: true,
+      connect_timeout: Duration::from_secs(10),
+      read_timeout: None,
+      proxy: None,
+      no_proxy: None,
+      ca_bundle: None
+    }
+  }
+}
+
+pub fn make_client(config: &ClientConfig) -> Result<reqwest::Client, Error>
 {
   let cb = reqwest::Client::builder()
-    .connect_timeout(Duration::from_secs(timeout as u64));
-  let cb = match user_agent {
-    Some(UserAgent::Fixed(s)) => cb.user_agent(s),
+    .connect_timeout(config.connect_timeout);
+  let cb = match &config.user_agent {
+    Some(user_agent) => cb.user_agent(user_agent.value()),
     None => cb
   };
-  let cb = if redirect {
+  let cb = if config.redirect {
     cb.redirect(Policy::default())
   } else {
     cb.redirect(Policy::none())
   };
+  // without an explicit proxy reqwest still honors HTTP_PROXY, HTTPS_PROXY and NO_PROXY,
+  // but an explicit no-proxy list needs the proxies of the environment set up around it
+  let cb = match (&config.proxy, &config.no_proxy) {
+    (Some(proxy), _) => {
+      let no_proxy = match &config.no_proxy {
+        Some(list) => NoProxy::from_string(list),
+        None => NoProxy::from_env()
+      };
+      cb.proxy(Proxy::all(proxy.as_str())
+        .with_context(|| format!("invalid proxy url: {}", proxy))?
+        .no_proxy(no_proxy))
+    },
+    (None, Some(list)) => env_proxies(list)?
+      .into_iter()
+      .fold(cb, |cb, proxy| cb.proxy(proxy)),
+    (None, None) => cb
+  };
+  let cb = match &config.ca_bundle {
+    Some(path) => {
+      let pem = std::fs::read(path)
+        .with_context(|| format!("failed to read ca bundle {}", path))?;
+      let certificates = Certificate::from_pem_bundle(&pem)
+        .with_context(|| format!("invalid ca bundle {}", path))?;
+      ensure!(!certificates.is_empty(), "no certificates found in ca bundle {}", path);
+      certificates
+        .into_iter()
+        .fold(cb, |cb, certificate| cb.add_root_certificate(certificate))
+    },
+    None => cb
+  };
 
   let client = cb.build()?;
   Ok(client)
-}
\ No newline at end of file
+}
+
+/// Whether `host` is covered by a comma-separated no-proxy list of hosts and domains.
+pub fn bypasses_proxy(no_proxy: &str, host: &str) -> bool
+{
+  let host = host.to_ascii_lowercase();
+  no_proxy
+    .split(',')
+    .map(|entry| entry.trim().trim_start_matches('.').to_ascii_lowercase())
+    .filter(|entry| !entry.is_empty())
+    .any(|entry| entry == "*" || host == entry || host.ends_with(&format!(".{}", entry)))
+}
+
+/// Proxies of `HTTP_PROXY` and `HTTPS_PROXY` (or their lowercase forms), bypassed for the hosts in `no_proxy`.
+fn env_proxies(no_proxy: &str) -> Result<Vec<Proxy>, Error>
+{
+  let from_env = |names: [&str; 2]| names
+    .into_iter()
+    .find_map(|name| std::env::var(name).ok().filter(|value| !value.is_empty()));
+  let mut proxies = Vec::new();
+  if let Some(url) = from_env(["HTTP_PROXY", "http_proxy"]) {
+    proxies.push(Proxy::http(url.as_str())
+      .with_context(|| format!("invalid proxy url in HTTP_PROXY: {}", url))?
+      .no_proxy(NoProxy::from_string(no_proxy)));
+  }
+  if let Some(url) = from_env(["HTTPS_PROXY", "https_proxy"]) {
+    proxies.push(Proxy::https(url.as_str())
+      .with_context(|| format!("invalid proxy url in HTTPS_PROXY: {}", url))?
+      .no_proxy(NoProxy::from_string(no_proxy)));
+  }
+  Ok(proxies)
+}
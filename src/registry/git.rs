@@ -1,39 +1,341 @@
-use anyhow::Error;
+use std::path::Path;
+use anyhow::{bail, Context, Error};
 use colored::Colorize;
 use git2_credentials::CredentialHandler;
+use url::Url;
 use crate::{log, warn};
+use crate::registry::client_builder::{bypasses_proxy, ClientConfig};
 
-pub fn clone_repo(url: &str, target_path: &str) -> Result<(), Error>
+const REMOTE_NAME: &str = "origin";
+const SHALLOW_DEPTH: i32 = 1;
+// GIT_FETCH_DEPTH_UNSHALLOW, a depth of 0 would leave the history as it is
+const UNSHALLOW_DEPTH: i32 = i32::MAX;
+pub const DEFAULT_BRANCH: &str = "main";
+
+fn fix_url(url: &str) -> String
 {
-  log!("cloning git repository from {} to {}", url, target_path);
-  let url = if !url.ends_with(".git") {
+  if !url.ends_with(".git") && !Path::new(url).exists() {
     warn!("fixing url: {}{}", url, ".git".red().bold());
     format!("{}.git", url)
   } else {
     url.to_string()
+  }
+}
+
+fn is_commit_sha(reference: &str) -> bool
+{
+  (7..=40).contains(&reference.len()) && reference.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// The configured proxy, unless `url` is in the no-proxy list. Without one, libgit2 looks
+/// for a proxy in the git config and the environment. libgit2 only proxies https remotes.
+fn proxy_options<'a>(url: &str, config: &ClientConfig) -> git2::ProxyOptions<'a>
+{
+  let mut po = git2::ProxyOptions::new();
+  let bypassed = Url::parse(url)
+    .ok()
+    .and_then(|url| url.host_str().map(String::from))
+    .zip(config.no_proxy.as_ref())
+    .is_some_and(|(host, no_proxy)| bypasses_proxy(no_proxy, host.as_str()));
+  match (&config.proxy, bypassed) {
+    (_, true) => {},
+    (Some(proxy), false) => {
+      po.url(proxy);
+    },
+    (None, false) => {
+      po.auto();
+    }
+  }
+  po
+}
+
+/// Makes libgit2 trust the certificates of the configured ca bundle too.
+fn apply_ca_bundle(config: &ClientConfig)
+{
+  let Some(path) = &config.ca_bundle else {
+    return;
   };
+  // SAFETY: libgit2 options are set before any fetch starts and fdm clones one repository at a time
+  if let Err(e) = unsafe { git2::opts::set_ssl_cert_file(path) } {
+    warn!("ca bundle {} is not used for git registries ({})", path, e.message());
+  }
+}
+
+fn fetch_options<'a>(url: &str, depth: i32, config: &ClientConfig) -> Result<git2::FetchOptions<'a>, Error>
+{
   let mut cb = git2::RemoteCallbacks::new();
-  let git_config = git2::Config::open_default().unwrap();
+  let git_config = git2::Config::open_default()?;
   let mut ch = CredentialHandler::new(git_config);
   cb.credentials(move |url, username, allowed|
     ch.try_next_credential(url, username, allowed)
   );
   let mut fo = git2::FetchOptions::new();
   fo.remote_callbacks(cb)
-    .download_tags(git2::AutotagOption::All)
+    .proxy_options(proxy_options(url, config))
+    .download_tags(git2::AutotagOption::None)
     .update_fetchhead(true);
-  std::fs::create_dir_all(&target_path)?;
-  git2::build::RepoBuilder::new()
-    .branch("main")
-    .fetch_options(fo)
-    .clone(url.as_str(), target_path.as_ref())?;
+  if depth != 0 {
+    fo.depth(depth);
+  }
+  Ok(fo)
+}
+
+fn fetch(remote: &mut git2::Remote, refspecs: &[String], depth: i32, config: &ClientConfig) -> Result<(), git2::Error>
+{
+  let refspecs = refspecs
+    .iter()
+    .map(|refspec| refspec.as_str())
+    .collect::<Vec<&str>>();
+  let url = remote.url().unwrap_or_default().to_string();
+  let mut full = fetch_options(url.as_str(), 0, config)
+    .map_err(|e| git2::Error::from_str(e.to_string().as_str()))?;
+  if depth != 0 {
+    let mut depth_options = fetch_options(url.as_str(), depth, config)
+      .map_err(|e| git2::Error::from_str(e.to_string().as_str()))?;
+    // not every transport supports shallow fetches (local paths, dumb http)
+    if remote.fetch(&refspecs, Some(&mut depth_options), None).is_ok() {
+      return Ok(());
+    }
+  }
+  remote.fetch(&refspecs, Some(&mut full), None)
+}
+
+/// Fetches `reference` (a branch, tag or commit sha) and returns the commit it resolves to.
+fn fetch_reference<'r>(repo: &'r git2::Repository, url: &str, reference: &str, config: &ClientConfig)
+  -> Result<git2::Commit<'r>, Error>
+{
+  match repo.find_remote(REMOTE_NAME) {
+    Ok(remote) if remote.url() == Some(url) => {},
+    Ok(_) => repo.remote_set_url(REMOTE_NAME, url)?,
+    Err(_) => {
+      repo.remote(REMOTE_NAME, url)?;
+    }
+  };
+  let mut remote = repo.find_remote(REMOTE_NAME)?;
+  log!("fetching {} from {}", reference.bold(), url);
+  apply_ca_bundle(config);
+  if is_commit_sha(reference) {
+    if let Ok(commit) = repo.revparse_single(reference).and_then(|obj| obj.peel_to_commit()) {
+      return Ok(commit);
+    }
+    // older commits are missing from a shallow clone
+    let depth = match repo.is_shallow() {
+      true => UNSHALLOW_DEPTH,
+      false => 0
+    };
+    if let Err(e) = fetch(&mut remote, &[format!("+refs/heads/*:refs/remotes/{}/*", REMOTE_NAME)], depth, config) {
+      warn!("failed to fetch registry updates ({})", e.message());
+    }
+    return repo
+      .revparse_single(reference)
+      .and_then(|obj| obj.peel_to_commit())
+      .with_context(|| format!("commit {} not found in registry repository", reference));
+  }
+  // on network errors whatever was fetched for this reference last time is used
+  if let Err(e) = fetch(&mut remote, &[
+    format!("+refs/heads/{0}:refs/remotes/{1}/{0}", reference, REMOTE_NAME),
+    format!("+refs/tags/{0}:refs/tags/{0}", reference)
+  ], SHALLOW_DEPTH, config) {
+    warn!("failed to fetch registry updates ({}), using previously fetched state", e.message());
+  }
+  for name in [
+    format!("refs/remotes/{}/{}", REMOTE_NAME, reference),
+    format!("refs/tags/{}", reference)
+  ] {
+    if let Ok(commit) = repo.find_reference(name.as_str()).and_then(|r| r.peel_to_commit()) {
+      return Ok(commit);
+    }
+  }
+  bail!("branch or tag {} not found in registry repository {}", reference, url)
+}
+
+fn checkout(repo: &git2::Repository, commit: &git2::Commit) -> Result<(), Error>
+{
+  repo.checkout_tree(commit.as_object(), Some(git2::build::CheckoutBuilder::new()
+    .force()
+    .remove_untracked(true)
+  ))?;
+  repo.set_head_detached(commit.id())?;
   Ok(())
 }
 
-pub fn pull_repo_main(url: &str, target_path: &str) -> Result<(), Error>
+/// Clones the repository and checks out `reference`, returning the resolved commit sha.
+pub fn clone_repo(url: &str, target_path: &str, reference: &str, config: &ClientConfig) -> Result<String, Error>
+{
+  log!("cloning git repository from {} to {}", url, target_path);
+  let url = fix_url(url);
+  std::fs::create_dir_all(target_path)?;
+  let repo = git2::Repository::init(target_path)?;
+  let commit = fetch_reference(&repo, url.as_str(), reference, config)?;
+  checkout(&repo, &commit)?;
+  Ok(commit.id().to_string())
+}
+
+/// Fetches `reference` into the existing working copy and hard resets to it, returning
+/// the resolved commit sha. Falls back to a fresh clone only when the working copy is
+/// missing or unreadable.
+pub fn update_repo(url: &str, target_path: &str, reference: &str, config: &ClientConfig) -> Result<String, Error>
 {
-  log!("removing old git repository at {}", target_path);
-  std::fs::remove_dir_all(target_path)?;
-  clone_repo(url, target_path)?;
+  let repo = match git2::Repository::open(target_path) {
+    Ok(repo) if !repo.is_bare() && repo.head().is_ok() => repo,
+    _ => {
+      warn!("git repository at {} is missing or corrupted, cloning it again", target_path);
+      if Path::new(target_path).exists() {
+        std::fs::remove_dir_all(target_path)?;
+      }
+      return clone_repo(url, target_path, reference, config);
+    }
+  };
+  let url = fix_url(url);
+  let commit = fetch_reference(&repo, url.as_str(), reference, config)?;
+  checkout(&repo, &commit)?;
+  Ok(commit.id().to_string())
+}
+
+pub fn head_commit(path: &str) -> Result<String, Error>
+{
+  Ok(git2::Repository::open(path)?
+    .head()?
+    .peel_to_commit()?
+    .id()
+    .to_string())
+}
+
+pub fn clone_local(path: &str, target_path: &str) -> Result<(), Error>
+{
+  log!("checking out local git repository from {} to {}", path, target_path);
+  if Path::new(target_path).exists() {
+    std::fs::remove_dir_all(target_path)?;
+  }
+  std::fs::create_dir_all(target_path)?;
+  git2::build::RepoBuilder::new()
+    .clone(path, target_path.as_ref())?;
   Ok(())
 }
+
+
+#[cfg(test)]
+mod tests
+{
+  use std::io::{BufRead, BufReader, Read, Write};
+  use std::net::TcpListener;
+  use std::path::PathBuf;
+  use std::process::{Command, Stdio};
+  use super::*;
+
+  /// Serves the repositories in `root` over smart http through `git http-backend`.
+  fn serve(root: PathBuf) -> String
+  {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}/", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+      for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut headers = Vec::new();
+        loop {
+          let mut line = String::new();
+          if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+            break;
+          }
+          if let Some((name, value)) = line.trim_end().split_once(':') {
+            headers.push((name.to_ascii_lowercase(), value.trim().to_string()));
+          }
+        }
+        let header = |name: &str| headers
+          .iter()
+          .find(|(key, _)| key == name)
+          .map(|(_, value)| value.clone())
+          .unwrap_or_default();
+        let mut body = vec![0; header("content-length").parse().unwrap_or(0)];
+        reader.read_exact(&mut body).unwrap();
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default();
+        let target = parts.next().unwrap_or_default();
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let mut backend = Command::new("git")
+          .arg("http-backend")
+          .env("GIT_PROJECT_ROOT", &root)
+          .env("GIT_HTTP_EXPORT_ALL", "1")
+          .env("REQUEST_METHOD", method)
+          .env("PATH_INFO", path)
+          .env("QUERY_STRING", query)
+          .env("CONTENT_TYPE", header("content-type"))
+          .env("CONTENT_LENGTH", body.len().to_string())
+          .stdin(Stdio::piped())
+          .stdout(Stdio::piped())
+          .spawn()
+          .unwrap();
+        backend.stdin.take().unwrap().write_all(&body).unwrap();
+        let output = backend.wait_with_output().unwrap().stdout;
+        let split = output
+          .windows(4)
+          .position(|window| window == b"\r\n\r\n")
+          .map(|pos| (pos, 4))
+          .or_else(|| output.windows(2).position(|window| window == b"\n\n").map(|pos| (pos, 2)))
+          .unwrap();
+        let cgi_headers = String::from_utf8_lossy(&output[..split.0]).to_string();
+        let content = &output[split.0 + split.1..];
+        let status = cgi_headers
+          .lines()
+          .find_map(|line| line.strip_prefix("Status: "))
+          .unwrap_or("200 OK")
+          .to_string();
+        let cgi_headers = cgi_headers
+          .lines()
+          .filter(|line| !line.starts_with("Status: "))
+          .map(|line| format!("{}\r\n", line))
+          .collect::<String>();
+        write!(stream, "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n", status, cgi_headers, content.len()).unwrap();
+        stream.write_all(content).unwrap();
+      }
+    });
+    base
+  }
+
+  /// Bare repository with a `main` branch of `count` commits, oldest first.
+  fn origin(path: &Path, count: usize) -> Vec<git2::Oid>
+  {
+    let repo = git2::Repository::init_bare(path).unwrap();
+    let signature = git2::Signature::now("fdm", "fdm@localhost").unwrap();
+    let mut commits: Vec<git2::Oid> = Vec::new();
+    for i in 0..count {
+      let blob = repo.blob(format!("{}", i).as_bytes()).unwrap();
+      let mut tree = repo.treebuilder(None).unwrap();
+      tree.insert("version", blob, 0o100644).unwrap();
+      let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+      let parents = commits
+        .last()
+        .map(|parent| vec![repo.find_commit(*parent).unwrap()])
+        .unwrap_or_default();
+      let parents = parents.iter().collect::<Vec<&git2::Commit>>();
+      commits.push(repo
+        .commit(Some("refs/heads/main"), &signature, &signature, &format!("commit {}", i), &tree, &parents)
+        .unwrap());
+    }
+    commits
+  }
+
+  #[test]
+  fn pins_old_commit_after_shallow_clone()
+  {
+    let dir = std::env::temp_dir().join(format!("fdm-git-shallow-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let commits = origin(&dir.join("origin.git"), 3);
+    let url = format!("{}origin.git", serve(dir.clone()));
+    let checkout = dir.join("checkout");
+    let checkout = checkout.to_str().unwrap();
+    let config = ClientConfig::default();
+
+    let head = clone_repo(&url, checkout, DEFAULT_BRANCH, &config).unwrap();
+    assert_eq!(head, commits[2].to_string());
+    assert!(git2::Repository::open(checkout).unwrap().is_shallow());
+
+    let pinned = update_repo(&url, checkout, &commits[0].to_string(), &config).unwrap();
+    assert_eq!(pinned, commits[0].to_string());
+    assert!(!git2::Repository::open(checkout).unwrap().is_shallow());
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
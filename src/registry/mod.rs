@@ -1,9 +1,20 @@
 pub mod registry;
 pub mod git;
 pub mod client_builder;
+pub mod resolver;
+pub mod source;
+pub mod sparse;
+pub mod auth;
 
 pub use registry::
 {
   Registry,
   REGISTRY
+};
+pub use resolver::Resolver;
+pub use source::
+{
+  RegistrySpec,
+  SourceKind,
+  DEFAULT_REGISTRY_NAME
 };
\ No newline at end of file
@@ -1,24 +1,28 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::path::Path;
 use std::sync::Mutex;
-use anyhow::{bail, Context, ensure, Error};
+use anyhow::{bail, ensure, Context, Error};
 use colored::Colorize;
 use lazy_static::lazy_static;
 use url::Url;
-use walkdir::WalkDir;
 use yaml_rust::Yaml;
 use crate::config::{CONFIG, wd};
 use crate::consts::{FDM_DIRECTORY_NAME, FDM_REGISTRY_NAME};
 use crate::log;
-use crate::registry::client_builder::{make_client, UserAgent};
+use crate::registry::client_builder::{make_client, ClientConfig};
+use crate::registry::source::RegistrySource;
 use crate::types::{
+  Artifact,
   Distribution,
+  ExtractLayout,
+  LinkInfo,
   PlatformArch,
   RegistryIndex,
   Descriptor,
-  Version
+  Version,
+  VersionReq
 };
-use crate::types::dependencies::Dependency;
+use crate::types::dependencies::{Dependency, ResolvedDependency};
 
 lazy_static!
 {
@@ -28,7 +32,7 @@ lazy_static!
 pub struct Registry
 {
   pub path: String,
-  pub index: HashMap<String, RegistryIndex>,
+  pub sources: Vec<RegistrySource>,
   pub client: reqwest::Client
 }
 
@@ -39,8 +43,8 @@ impl Default for Registry
     Self
     {
       path: String::from(""),
-      index: HashMap::new(),
-      client: make_client(&Some(UserAgent::default()), true, 10).unwrap()
+      sources: Vec::new(),
+      client: make_client(&ClientConfig::default()).unwrap()
     }
   }
 }
@@ -58,104 +62,84 @@ impl Registry
     Ok(Self
     {
       path,
-      index: HashMap::new(),
-      client: make_client(&Some(UserAgent::default()), true, 10)?
+      sources: Vec::new(),
+      client: make_client(&ClientConfig::default())?
     })
   }
 
-  pub fn init_registry(&mut self) -> Result<&Self, Error>
+  /// Initializes all registries. `roots` are the manifest's direct dependencies, used to
+  /// fetch only the reachable part of sparse registries.
+  pub fn init_registry(&mut self, roots: &HashMap<String, Dependency>) -> Result<&Self, Error>
   {
-    log!("initializing registry at: {}", self.path);
-
-    match self.seek_registry() {
-      Ok(_) => {
-        log!("found existing registry");
-        self.update_registry()?;
-      },
-      Err(e) => {
-        log!("{}", e);
-        log!("creating registry folder");
-        std::fs::create_dir_all(self.path.as_str())?;
-        self.clone_registry()?;
-      }
+    let (specs, offline, client_config) = {
+      let cfg = CONFIG
+        .lock()
+        .unwrap();
+      (cfg.registries(), cfg.is_offline(), cfg.client_config()?)
     };
-    for yaml in self.collect_yaml()? {
-      self.index.insert(yaml.0, Self::parse_yaml(&yaml.1)?);
+    self.client = make_client(&client_config)?;
+    self.remove_legacy_checkout()?;
+    self.sources = specs
+      .into_iter()
+      .map(|spec| RegistrySource::new(spec, self.path.as_str()))
+      .collect();
+    for source in &mut self.sources {
+      source.init(offline, &self.client, &client_config)?;
     }
+    self.load_reachable(roots)?;
 
     log!("{}" , "registry initialized!".green().bold());
     Ok(self)
   }
 
-  fn seek_registry(&self) -> Result<(), Error>
+  // older versions cloned the only registry straight into fdm/reg
+  fn remove_legacy_checkout(&self) -> Result<(), Error>
   {
-    if !Path::new(self.path.as_str()).exists() {
-      bail!("registry not found at: {}", self.path);
+    if Path::new(self.path.as_str()).join(".git").exists() {
+      log!("removing registry checkout left by an older fdm version at {}", self.path);
+      std::fs::remove_dir_all(self.path.as_str())?;
     }
-    if std::fs::read_dir(self.path.as_str())?.next().is_none() {
-      bail!("registry is empty at: {}", self.path);
-    }
-    Ok(())
-  }
-
-  // online only
-  fn clone_registry(&self) -> Result<(), Error>
-  {
-    let cfg = CONFIG
-      .lock()
-      .unwrap();
-    let url = cfg
-      .online_registry_url
-      .as_str();
-    log!("cloning registry from: {}", url);
-    crate::registry::git::clone_repo(url, self.path.as_str())?;
     Ok(())
   }
 
-  // online only
-  fn update_registry(&self) -> Result<(), Error>
+  fn load_reachable(&mut self, roots: &HashMap<String, Dependency>) -> Result<(), Error>
   {
-    let cfg = CONFIG
-      .lock()
-      .unwrap();
-    let url = cfg
-      .online_registry_url
-      .as_str();
-    log!("updating registry from: {}", url);
-    crate::registry::git::pull_repo_main(url, self.path.as_str())?;
+    let mut queue = roots
+      .iter()
+      .map(|(name, dep)| (name.clone(), dep.registry.clone()))
+      .collect::<VecDeque<(String, Option<String>)>>();
+    let mut visited = HashSet::new();
+    while let Some((name, registry)) = queue.pop_front() {
+      if !visited.insert((name.clone(), registry.clone())) {
+        continue;
+      }
+      for source in self.sources
+        .iter_mut()
+        .filter(|source| registry.as_ref().is_none_or(|r| r == source.name()))
+      {
+        if !source.load_package(&name)? {
+          continue;
+        }
+        for descriptor in source.index[&name].versions.values() {
+          for (dep_name, dep) in &descriptor.dependencies {
+            queue.push_back((dep_name.clone(), dep.registry.clone()));
+          }
+        }
+        break;
+      }
+    }
     Ok(())
   }
 
-  fn collect_yaml(&self) -> Result<Vec<(String, Vec<Yaml>)>, Error>
+  pub fn source(&self, name: &str) -> Result<&RegistrySource, Error>
   {
-    log!("collecting yaml files from registry");
-    let mut yaml_files = vec![];
-    for entry in WalkDir::new(self.path.as_str())
-      .into_iter()
-      .filter_map(|e| e.ok())
-      .filter(|e| e.file_type().is_file()
-        && e.path().extension().is_some()
-        && e.path().extension().unwrap() == "yml"
-      )
-    {
-      let content = std::fs::read_to_string(entry.path())?;
-      let yaml = yaml_rust::YamlLoader::load_from_str(content.as_str())?;
-      yaml_files.push((
-        entry
-          .path()
-          .file_stem()
-          .context("file stem is none")?
-          .to_os_string()
-          .into_string()
-          .expect("os string to string should not fail"),
-        yaml
-      ));
-    }
-    log!("found {} yaml files", yaml_files.len());
-    Ok(yaml_files)
+    self.sources
+      .iter()
+      .find(|source| source.name() == name)
+      .context(format!("registry {} is not configured", name))
   }
 
-  fn parse_yaml(yaml: &Vec<Yaml>) -> Result<RegistryIndex, Error>
+  pub(crate) fn parse_yaml(yaml: &Vec<Yaml>) -> Result<RegistryIndex, Error>
   {
     let mut index = RegistryIndex::default();
     for hash in yaml
@@ -167,11 +151,12 @@ impl Registry
       {
         let version = Version::try_from(version
           .as_str()
-          .context("version is none")?)?;
+          .context(format!("version key {:?} must be a quoted or plain MAJOR.MINOR.PATCH string", version))?)?;
         let descriptor_hash = descriptor
           .as_hash()
           .context("descriptor is none")?;
         let mut distribution = HashMap::new();
+        let mut link = HashMap::new();
         let mut dependencies = HashMap::new();
         for (key, value) in descriptor_hash
         {
@@ -203,8 +188,9 @@ impl Registry
                       .as_str()
                       .context("value is none")?;
                     match key {
-                      "version" => dependency_struct.version = Version::try_from(value)?,
+                      "version" => dependency_struct.version = VersionReq::try_from(value)?,
                       "distribution" => dependency_struct.distribution = Distribution::try_from(value)?,
+                      "registry" => dependency_struct.registry = Some(value.to_string()),
                       _ => bail!("unknown key: {}", key)
                     }
                   }
@@ -213,34 +199,58 @@ impl Registry
               }
             },
             "source" => {
-              let value = value
-                .as_str()
-                .context("value is none (sources)")?;
-              let url = Url::parse(value)?;
+              // link metadata sits next to url and sha256
+              let mut source_link = LinkInfo::default();
+              let artifact = match value.as_hash() {
+                Some(hash) => {
+                  let mut artifact = yaml_rust::yaml::Hash::new();
+                  for (key, value) in hash
+                  {
+                    match key.as_str() {
+                      Some(key) if LinkInfo::KEYS.contains(&key) => Self::parse_link_key(&mut source_link, key, value)
+                        .context("invalid source link metadata")?,
+                      _ => { artifact.insert(key.clone(), value.clone()); }
+                    }
+                  }
+                  Yaml::Hash(artifact)
+                },
+                None => value.clone()
+              };
               distribution.insert(
                 Distribution::Sources,
                 HashMap::from([(
                   PlatformArch::Any,
-                  url
+                  Self::parse_artifact(&artifact).context("invalid source artifact")?
                 )])
               );
+              if !source_link.is_empty() {
+                link.insert(Distribution::Sources, source_link);
+              }
             }
             _ => {
               let value = value
                 .as_hash()
                 .context("value is none (other)")?;
-              for (platform, url) in value
+              let dist = Distribution::try_from(key.as_str().context("key is none")?)?;
+              // link metadata sits next to the platforms
+              let mut dist_link = LinkInfo::default();
+              for (platform, artifact) in value
               {
-                let platform = PlatformArch::try_from(platform
-                  .as_str()
-                  .context("platform is none")?)?;
-                let url = url
+                let platform = platform
                   .as_str()
-                  .context("url is none")?;
-                distribution.insert(
-                  Distribution::try_from(key.as_str().context("key is none")?)?,
-                  HashMap::from([(platform, Url::parse(url)?)])
-                );
+                  .context("platform is none")?;
+                if LinkInfo::KEYS.contains(&platform) {
+                  Self::parse_link_key(&mut dist_link, platform, artifact)
+                    .with_context(|| format!("invalid {} link metadata", dist))?;
+                  continue;
+                }
+                distribution
+                  .entry(dist.clone())
+                  .or_insert_with(HashMap::new)
+                  .insert(PlatformArch::try_from(platform)?, Self::parse_artifact(artifact).context("invalid artifact")?);
+              }
+              if !dist_link.is_empty() {
+                link.insert(dist, dist_link);
               }
             }
           }
@@ -249,6 +259,7 @@ impl Registry
           version,
           Descriptor {
             distribution,
+            link,
             dependencies
           }
         );
@@ -257,13 +268,207 @@ impl Registry
     Ok(index)
   }
 
+  /// An artifact is a url, a list of mirror urls, or a table with `url` (one or a list)
+  /// and `sha256` keys.
+  fn parse_artifact(yaml: &Yaml) -> Result<Artifact, Error>
+  {
+    if yaml.as_str().is_some() || yaml.as_vec().is_some() {
+      return Ok(Artifact { urls: Self::parse_urls(yaml)?, sha256: None, layout: ExtractLayout::default() });
+    }
+    let hash = yaml
+      .as_hash()
+      .context("artifact must be a url, a list of urls or a table with url, sha256, strip and subdir")?;
+    let mut urls = None;
+    let mut sha256 = None;
+    let mut strip = None;
+    let mut subdir = None;
+    for (key, value) in hash
+    {
+      match key.as_str().context("key is none")? {
+        "url" => urls = Some(Self::parse_urls(value)?),
+        "sha256" => {
+          let value = value
+            .as_str()
+            .context("sha256 must be a string")?;
+          ensure!(value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit()),
+            "sha256 must be 64 hex digits, got: {}", value
+          );
+          sha256 = Some(value.to_ascii_lowercase());
+        },
+        "strip" => strip = Some(value
+          .as_i64()
+          .and_then(|value| usize::try_from(value).ok())
+          .context("strip must be a non-negative integer")?),
+        "subdir" => subdir = Some(value
+          .as_str()
+          .context("subdir must be a string")?
+          .to_string()),
+        key => bail!("unknown key: {}", key)
+      }
+    }
+    Ok(Artifact
+    {
+      urls: urls.context("artifact url is missing")?,
+      sha256,
+      layout: ExtractLayout::new(strip, subdir)?
+    })
+  }
+
+  fn parse_link_key(link: &mut LinkInfo, key: &str, yaml: &Yaml) -> Result<(), Error>
+  {
+    match key {
+      "include" => link.include = Self::parse_strings(yaml).context("include must be a string or a list of strings")?,
+      "libs" => link.libs = Self::parse_strings(yaml).context("libs must be a string or a list of strings")?,
+      "defines" => link.defines = Self::parse_strings(yaml).context("defines must be a string or a list of strings")?,
+      "system_libs" => link.system_libs = Self::parse_strings(yaml).context("system_libs must be a string or a list of strings")?,
+      "components" => {
+        // a list names components made of the library with the same name
+        link.components = match yaml {
+          Yaml::Hash(hash) => hash
+            .iter()
+            .map(|(name, libs)| Ok((
+              name.as_str().context("component name must be a string")?.to_string(),
+              Self::parse_strings(libs).context("component libs must be a string or a list of strings")?
+            )))
+            .collect::<Result<_, Error>>()?,
+          _ => Self::parse_strings(yaml)
+            .context("components must be a list of names or a table of names and libs")?
+            .into_iter()
+            .map(|name| (name.clone(), vec![name]))
+            .collect()
+        };
+        ensure!(link.components.values().all(|libs| !libs.is_empty()), "component without libs");
+      },
+      key => bail!("unknown link key: {}", key)
+    }
+    Ok(())
+  }
+
+  fn parse_strings(yaml: &Yaml) -> Result<Vec<String>, Error>
+  {
+    match yaml {
+      Yaml::String(value) => Ok(vec![value.clone()]),
+      Yaml::Array(values) => values
+        .iter()
+        .map(|value| Ok(value.as_str().context("value is not a string")?.to_string()))
+        .collect(),
+      _ => bail!("expected a string or a list of strings")
+    }
+  }
+
+  fn parse_urls(yaml: &Yaml) -> Result<Vec<Url>, Error>
+  {
+    let urls = match yaml {
+      Yaml::String(url) => vec![Url::parse(url)?],
+      Yaml::Array(urls) => urls
+        .iter()
+        .map(|url| Ok(Url::parse(url.as_str().context("url is none")?)?))
+        .collect::<Result<Vec<Url>, Error>>()?,
+      _ => bail!("url must be a string or a list of strings")
+    };
+    ensure!(!urls.is_empty(), "artifact has no urls");
+    Ok(urls)
+  }
+
   pub fn dump_to_cli(&self) -> Result<(), Error>
   {
     println!();
     log!("{}", "-- registry index --".cyan().bold());
-    for (name, reg_index) in &self.index
+    for source in &self.sources
+    {
+      log!("registry {}: {}", source.name().cyan().bold(), source.spec.kind);
+      if let Some(commit) = &source.commit {
+        log!("registry index state: {} at commit {}",
+          source.spec.reference().purple().bold(),
+          commit.as_str().bold()
+        );
+      }
+      Self::dump_index(source);
+    }
+    Ok(())
+  }
+
+  /// Prints everything the registries know about one package.
+  pub fn dump_package(&self, name: &str) -> Result<(), Error>
+  {
+    println!();
+    log!("{}", format!("-- package {} --", name).cyan().bold());
+    let sources = self.sources
+      .iter()
+      .filter(|source| source.index.contains_key(name))
+      .collect::<Vec<&RegistrySource>>();
+    ensure!(!sources.is_empty(), "package {} not found in any registry", name);
+    for source in sources
+    {
+      log!("registry {}:", source.name().cyan().bold());
+      let mut versions = source.index[name].versions
+        .iter()
+        .collect::<Vec<(&Version, &Descriptor)>>();
+      versions.sort_by(|a, b| b.0.cmp(a.0));
+      for (version, descriptor) in versions
+      {
+        log!("  {}", version.to_string().green().bold());
+        let mut distributions = descriptor.distribution
+          .iter()
+          .collect::<Vec<(&Distribution, &HashMap<PlatformArch, Artifact>)>>();
+        distributions.sort_by_key(|(distribution, _)| distribution.to_string());
+        for (distribution, artifacts) in distributions
+        {
+          let mut artifacts = artifacts
+            .iter()
+            .collect::<Vec<(&PlatformArch, &Artifact)>>();
+          artifacts.sort_by_key(|(platform, _)| platform.to_string());
+          for (platform, artifact) in artifacts
+          {
+            log!("    {}/{}: {}{}",
+              distribution.to_string().blue().bold(),
+              platform.to_string().italic(),
+              artifact.urls
+                .iter()
+                .map(|url| url.to_string())
+                .collect::<Vec<String>>()
+                .join(", "),
+              match &artifact.sha256 {
+                Some(sha256) => format!(" (sha256 {})", sha256),
+                None => String::new()
+              }
+            );
+          }
+          if let Some(link) = descriptor.link.get(distribution) {
+            for (key, values) in [
+              ("include", &link.include),
+              ("libs", &link.libs),
+              ("defines", &link.defines),
+              ("system libs", &link.system_libs)
+            ] {
+              if !values.is_empty() {
+                log!("      {}: {}", key, values.join(", "));
+              }
+            }
+            for (component, libs) in &link.components
+            {
+              log!("      component {}: {}", component.bold(), libs.join(", "));
+            }
+          }
+        }
+        let mut dependencies = descriptor.dependencies
+          .iter()
+          .collect::<Vec<(&String, &Dependency)>>();
+        dependencies.sort_by_key(|(name, _)| name.as_str());
+        for (dependency_name, dependency) in dependencies
+        {
+          log!("    {} {} {}", "➤ depends on".bright_purple(), dependency_name.bold(), dependency);
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn dump_index(source: &RegistrySource)
+  {
+    for (name, reg_index) in &source.index
     {
-      let mut str_to_print = format!("{}:", name.to_string().yellow().bold());
+      let mut str_to_print = format!("[{}] {}:", source.name(), name.to_string().yellow().bold());
       for (version, descriptor) in &reg_index.versions
       {
         str_to_print = format!(
@@ -313,91 +518,102 @@ impl Registry
       }
       log!("{}", str_to_print);
     }
-    Ok(())
   }
 
-  pub fn contains(&self, name: &str, dependency: &Dependency) -> bool
+  fn target_arch(&self, dependency: &Dependency) -> PlatformArch
   {
-    self.index.get(name)
-      .and_then(|reg_index| reg_index.versions.get(&dependency.version))
-      .and_then(|descriptor| descriptor.distribution.get(&dependency.distribution))
-      .map(|urls| {
-        if dependency.distribution == Distribution::Sources {
-          true
-        } else {
-          urls
-            .contains_key(&dependency
-              .arch
-              .as_ref()
-              .unwrap_or(&PlatformArch::from_env().unwrap_or(PlatformArch::Any))
-            )
-        }
-      }
-      ).unwrap_or(false)
+    if dependency.distribution == Distribution::Sources {
+      return PlatformArch::Any;
+    }
+    dependency
+      .arch
+      .clone()
+      .unwrap_or(CONFIG
+        .lock()
+        .unwrap()
+        .platform
+        .clone()
+      )
   }
 
-  pub fn get(&self, name: &str, dependency: &Dependency) -> Result<Url, Error>
+  fn artifact_for(descriptor: &Descriptor, distribution: &Distribution, arch: &PlatformArch) -> Option<Artifact>
   {
-    ensure!(self.contains(name, dependency), "dependency {} version {}/{}/{} not found in registry",
-            name,
-            dependency.distribution,
-            dependency.version,
-            dependency.arch.as_ref().unwrap_or(&PlatformArch::Any)
-    );
-    self.index.get(name)
-      .and_then(|reg_index| reg_index.versions.get(&dependency.version))
-      .and_then(|descriptor| descriptor.distribution.get(&dependency.distribution))
+    descriptor.distribution
+      .get(distribution)
       .and_then(|urls| urls
-        .get(&dependency
-          .arch
-          .as_ref()
-          .unwrap_or(&PlatformArch::Any)
-        )
-      ).context(format!("package {} version {}/{}/{} not found in registry",
-        name,
-        dependency.version,
-        dependency.distribution,
-        dependency
-          .arch
-          .as_ref()
-          .unwrap_or(&PlatformArch::Any)
-      )).map(|url| url.clone())
+        .get(arch)
+        .or(urls.get(&PlatformArch::Any))
+      )
+      .cloned()
   }
 
-  pub fn get_indirect_dependencies(&self, name: &str, dependency: &Dependency) -> Result<HashMap<String, Dependency>, Error>
+  /// Newest version of `name` matching `dependency` that is not `excluded`.
+  pub fn resolve(&self, name: &str, dependency: &Dependency, excluded: &[Version]) -> Result<ResolvedDependency, Error>
   {
-    let index = self.index.get(name);
-    if index.is_none() {
-      return Ok(HashMap::new());
-    }
-    let index = index.unwrap();
-    let desc = index.versions.get(&dependency.version);
-    if desc.is_none() {
-      return Ok(HashMap::new());
-    }
-    let desc = desc.unwrap();
-    let deps = &desc.dependencies;
-    if deps.is_empty() {
-      return Ok(HashMap::new());
-    }
-    Ok(deps.clone())
+    let arch = self.target_arch(dependency);
+    let sources = match &dependency.registry {
+      Some(registry) => vec![self.source(registry)?],
+      None => self.sources.iter().collect()
+    };
+    // the first registry in priority order that knows the package owns it
+    let source = sources
+      .into_iter()
+      .find(|source| source.index.contains_key(name))
+      .context(format!("package {} not found in {}", name, match &dependency.registry {
+        Some(registry) => format!("registry {}", registry),
+        None => String::from("any registry")
+      }))?;
+    let reg_index = &source.index[name];
+    let mut versions = reg_index.versions
+      .iter()
+      .filter(|(version, _)| dependency.version.matches(version) && !excluded.contains(version))
+      .collect::<Vec<(&Version, &Descriptor)>>();
+    versions.sort_by(|a, b| b.0.cmp(a.0));
+    versions
+      .into_iter()
+      .find_map(|(version, descriptor)| Self::artifact_for(descriptor, &dependency.distribution, &arch)
+        .map(|artifact| ResolvedDependency {
+          name: name.to_string(),
+          version: version.clone(),
+          distribution: dependency.distribution.clone(),
+          arch: arch.clone(),
+          url: artifact.urls[0].clone(),
+          mirrors: artifact.urls[1..].to_vec(),
+          checksum: artifact.sha256,
+          layout: artifact.layout,
+          depth: 0,
+          registry: source.name().to_string()
+        })
+      ).context(format!("no version of package {} matching {} ({}/{}) found in registry {}",
+        name,
+        dependency.version,
+        dependency.distribution,
+        arch,
+        source.name()
+      ))
   }
 
-  pub fn get_recursively(&self, name: &str, dependency: &Dependency) -> Result<HashMap<String, Dependency>, Error>
+  /// Link metadata of one distribution of a package version, empty if the registry has none.
+  pub fn get_link_info(&self, registry: &str, name: &str, version: &Version, distribution: &Distribution)
+    -> Result<LinkInfo, Error>
   {
-    let mut urls = HashMap::new();
-    self.get_recursively_internal(name, dependency, &mut urls)?;
-    Ok(urls)
+    Ok(self.source(registry)?
+      .index
+      .get(name)
+      .and_then(|index| index.versions.get(version))
+      .and_then(|desc| desc.link.get(distribution))
+      .cloned()
+      .unwrap_or_default())
   }
 
-  fn get_recursively_internal(&self, name: &str, dependency: &Dependency, urls: &mut HashMap<String, Dependency>)
-    -> Result<(), Error>
+  pub fn get_indirect_dependencies(&self, registry: &str, name: &str, version: &Version)
+    -> Result<HashMap<String, Dependency>, Error>
   {
-    let indirect = self.get_indirect_dependencies(name, dependency)?;
-    for (dep_name, dep) in indirect.iter() {
-      urls.insert(dep_name.clone(), dep.clone());
-      self.get_recursively_internal(dep_name, dep, urls)?;
-    }
-    Ok(())
+    Ok(self.source(registry)?
+      .index
+      .get(name)
+      .and_then(|index| index.versions.get(version))
+      .map(|desc| desc.dependencies.clone())
+      .unwrap_or_default())
   }
-}
\ No newline at end of file
+}
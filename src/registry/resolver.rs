@@ -0,0 +1,488 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::Display;
+use anyhow::{anyhow, bail, Error};
+use colored::Colorize;
+use crate::registry::Registry;
+use crate::warn;
+use crate::types::{Version, VersionReq};
+use crate::types::dependencies::{Dependency, ResolvedDependency};
+
+// every round can only add requirements reachable from the previous selection,
+// so a sane tree settles long before this
+const MAX_RESOLVE_ROUNDS: usize = 64;
+// every attempt excludes one more package version, this bounds the backtracking
+const MAX_RESOLVE_ATTEMPTS: usize = 256;
+
+type Selection = BTreeMap<String, ResolvedDependency>;
+type Requirements = BTreeMap<String, Vec<Requirement>>;
+
+#[derive(Debug, Clone)]
+struct Requirement
+{
+  pub dependency: Dependency,
+  pub path: Vec<String>,
+  /// package whose selected version brought this requirement in, `None` for the manifest
+  pub parent: Option<String>
+}
+
+/// A package no version could be selected for, with the selected versions of the
+/// packages whose requirements took part.
+struct Conflict
+{
+  error: Error,
+  culprits: Vec<(String, Version)>
+}
+
+impl From<Error> for Conflict
+{
+  fn from(error: Error) -> Self
+  {
+    Self
+    {
+      error,
+      culprits: Vec::new()
+    }
+  }
+}
+
+impl Display for Requirement
+{
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+  {
+    write!(f, "{} requires {} ({})",
+           self.path.join(" -> "),
+           self.dependency.version,
+           self.dependency.distribution)?;
+    if let Some(registry) = &self.dependency.registry {
+      write!(f, " from registry {}", registry)?;
+    }
+    Ok(())
+  }
+}
+
+/// Collects every requirement on every package reachable from the manifest and
+/// selects one version per package that satisfies all of them.
+pub struct Resolver<'a>
+{
+  registry: &'a Registry,
+  root: String
+}
+
+impl<'a> Resolver<'a>
+{
+  pub fn new(registry: &'a Registry, root: &str) -> Self
+  {
+    Self
+    {
+      registry,
+      root: root.to_string()
+    }
+  }
+
+  pub fn resolve(&self, direct: &HashMap<String, Dependency>) -> Result<Vec<ResolvedDependency>, Error>
+  {
+    let (mut selected, requirements) = self.search(direct, &HashMap::new(), &mut 0)?;
+    Self::warn_overrides(&requirements);
+    self.assign_depths(direct, &mut selected)?;
+    let mut resolved = selected
+      .into_values()
+      .collect::<Vec<ResolvedDependency>>();
+    resolved.sort_by(|a, b| a.depth
+      .cmp(&b.depth)
+      .then_with(|| a.name.cmp(&b.name))
+    );
+    Ok(resolved)
+  }
+
+  /// Settles on the newest versions that satisfy everything. On a conflict, each version that
+  /// took part is excluded in turn and the search starts over, so that an older version with
+  /// looser requirements can still win. Fails with the first conflict if nothing does.
+  fn search(&self,
+            direct: &HashMap<String, Dependency>,
+            excluded: &HashMap<String, Vec<Version>>,
+            attempts: &mut usize) -> Result<(Selection, Requirements), Error>
+  {
+    *attempts += 1;
+    let conflict = match self.settle(direct, excluded) {
+      Ok(settled) => return Ok(settled),
+      Err(conflict) => conflict
+    };
+    for (name, version) in conflict.culprits {
+      if *attempts >= MAX_RESOLVE_ATTEMPTS {
+        break;
+      }
+      let mut excluded = excluded.clone();
+      excluded
+        .entry(name)
+        .or_default()
+        .push(version);
+      if let Ok(settled) = self.search(direct, &excluded, attempts) {
+        return Ok(settled);
+      }
+    }
+    Err(conflict.error)
+  }
+
+  fn settle(&self, direct: &HashMap<String, Dependency>, excluded: &HashMap<String, Vec<Version>>)
+    -> Result<(Selection, Requirements), Conflict>
+  {
+    let mut selected = Selection::new();
+    for _ in 0..MAX_RESOLVE_ROUNDS {
+      let requirements = self.collect_requirements(direct, &selected)?;
+      let mut next = Selection::new();
+      for (name, reqs) in &requirements {
+        let excluded = excluded
+          .get(name)
+          .map(Vec::as_slice)
+          .unwrap_or_default();
+        let dependency = self
+          .select(name, reqs, excluded)
+          .map_err(|error| {
+            let mut culprits = Vec::new();
+            for parent in reqs.iter().filter_map(|req| selected.get(req.parent.as_ref()?)) {
+              let culprit = (parent.name.clone(), parent.version.clone());
+              if !culprits.contains(&culprit) {
+                culprits.push(culprit);
+              }
+            }
+            Conflict { error, culprits }
+          })?;
+        next.insert(name.clone(), dependency);
+      }
+      let settled = next.len() == selected.len() && next
+        .iter()
+        .all(|(name, dep)| selected
+          .get(name)
+          .is_some_and(|prev| prev.version == dep.version && prev.distribution == dep.distribution)
+        );
+      selected = next;
+      if settled {
+        return Ok((selected, requirements));
+      }
+    }
+    Err(anyhow!("dependency resolution did not settle after {} rounds", MAX_RESOLVE_ROUNDS).into())
+  }
+
+  fn warn_overrides(requirements: &Requirements)
+  {
+    for (name, reqs) in requirements {
+      let Some(direct) = reqs.iter().find(|req| req.parent.is_none()) else {
+        continue;
+      };
+      for req in reqs
+        .iter()
+        .filter(|req| req.dependency.distribution != direct.dependency.distribution
+          || req.dependency.registry != direct.dependency.registry)
+      {
+        warn!("distribution or registry of package {} is overridden by the manifest:\n\t{}\n\t{}", name, direct, req);
+      }
+    }
+  }
+
+  fn children(&self, dependency: &ResolvedDependency, selected: &Selection)
+    -> Result<Vec<String>, Error>
+  {
+    let mut names = self.registry
+      .get_indirect_dependencies(&dependency.registry, &dependency.name, &dependency.version)?
+      .into_keys()
+      .filter(|name| selected.contains_key(name))
+      .collect::<Vec<String>>();
+    names.sort();
+    Ok(names)
+  }
+
+  /// Fails on dependency cycles and sets each package's depth to the length of the
+  /// longest path from the manifest, so a package is always deeper than its dependents.
+  fn assign_depths(&self, direct: &HashMap<String, Dependency>, selected: &mut Selection)
+    -> Result<(), Error>
+  {
+    let mut roots = direct
+      .keys()
+      .cloned()
+      .collect::<Vec<String>>();
+    roots.sort();
+    let mut order = Vec::new();
+    let mut done = HashSet::new();
+    for root in &roots {
+      self.visit(root, selected, &mut Vec::new(), &mut done, &mut order)?;
+    }
+    let mut depths = roots
+      .iter()
+      .map(|name| (name.clone(), 1))
+      .collect::<HashMap<String, usize>>();
+    for name in order.iter().rev() {
+      let depth = depths.get(name).copied().unwrap_or(1);
+      for child in self.children(&selected[name], selected)? {
+        let entry = depths.entry(child).or_insert(0);
+        *entry = (*entry).max(depth + 1);
+      }
+    }
+    for (name, dependency) in selected.iter_mut() {
+      dependency.depth = depths.get(name).copied().unwrap_or(1);
+    }
+    Ok(())
+  }
+
+  fn visit(&self,
+           name: &str,
+           selected: &Selection,
+           stack: &mut Vec<String>,
+           done: &mut HashSet<String>,
+           order: &mut Vec<String>) -> Result<(), Error>
+  {
+    if let Some(pos) = stack.iter().position(|entry| entry == name) {
+      bail!("dependency cycle detected: {}", stack[pos..]
+        .iter()
+        .chain([&name.to_string()])
+        .map(|entry| format!("{} {}", entry, selected[entry].version))
+        .collect::<Vec<String>>()
+        .join(" -> ")
+      );
+    }
+    if done.contains(name) {
+      return Ok(());
+    }
+    stack.push(name.to_string());
+    for child in self.children(&selected[name], selected)? {
+      self.visit(&child, selected, stack, done, order)?;
+    }
+    stack.pop();
+    done.insert(name.to_string());
+    order.push(name.to_string());
+    Ok(())
+  }
+
+  fn collect_requirements(&self, direct: &HashMap<String, Dependency>, selected: &Selection)
+    -> Result<Requirements, Error>
+  {
+    let mut requirements = Requirements::new();
+    let mut queue = direct
+      .iter()
+      .collect::<BTreeMap<&String, &Dependency>>()
+      .into_iter()
+      .map(|(name, dep)| (name.clone(), Requirement {
+        dependency: dep.clone(),
+        path: vec![self.root.clone()],
+        parent: None
+      }))
+      .collect::<VecDeque<(String, Requirement)>>();
+    while let Some((name, req)) = queue.pop_front() {
+      let expanded = requirements.contains_key(&name);
+      let path = req.path.clone();
+      requirements
+        .entry(name.clone())
+        .or_default()
+        .push(req);
+      if expanded {
+        continue;
+      }
+      let Some(parent) = selected.get(&name) else {
+        continue;
+      };
+      let mut path = path;
+      path.push(format!("{} {}", name, parent.version));
+      let indirect = self.registry
+        .get_indirect_dependencies(&parent.registry, &name, &parent.version)?
+        .into_iter()
+        .collect::<BTreeMap<String, Dependency>>();
+      for (dep_name, dep) in indirect {
+        queue.push_back((dep_name, Requirement {
+          dependency: dep,
+          path: path.clone(),
+          parent: Some(name.clone())
+        }));
+      }
+    }
+    Ok(requirements)
+  }
+
+  fn select(&self, name: &str, reqs: &[Requirement], excluded: &[Version]) -> Result<ResolvedDependency, Error>
+  {
+    // the user's own requirement decides the distribution and registry and overrides those of
+    // transitive ones; without it, all transitive requirements must agree
+    let base = match reqs.iter().find(|req| req.parent.is_none()) {
+      Some(direct) => direct,
+      None => {
+        if let Some((a, b)) = reqs
+          .iter()
+          .zip(reqs.iter().skip(1))
+          .find(|(a, b)| a.dependency.distribution != b.dependency.distribution
+            || a.dependency.registry != b.dependency.registry)
+        {
+          bail!("conflicting distributions or registries requested for package {}:\n\t{}\n\t{}", name, a, b);
+        }
+        &reqs[0]
+      }
+    };
+    let combined = Self::combine(base, reqs.iter());
+    if let Ok(resolved) = self.registry.resolve(name, &combined, excluded) {
+      return Ok(resolved);
+    }
+    for (i, a) in reqs.iter().enumerate() {
+      if let Err(e) = self.registry.resolve(name, &Self::combine(base, [a]), excluded) {
+        bail!("{}\n\trequired by: {}", e, a);
+      }
+      for b in &reqs[i + 1..] {
+        if self.registry.resolve(name, &Self::combine(base, [a, b]), excluded).is_err() {
+          bail!("conflicting requirements for package {}: no version satisfies both\n\t{}\n\t{}", name, a, b);
+        }
+      }
+    }
+    bail!("conflicting requirements for package {}: no version satisfies all of\n\t{}",
+      name,
+      reqs
+        .iter()
+        .map(|req| req.to_string())
+        .collect::<Vec<String>>()
+        .join("\n\t")
+    )
+  }
+
+  fn combine<'r>(base: &Requirement, reqs: impl IntoIterator<Item = &'r Requirement>) -> Dependency
+  {
+    Dependency
+    {
+      version: VersionReq {
+        comparators: reqs
+          .into_iter()
+          .flat_map(|req| req.dependency.version.comparators.clone())
+          .collect()
+      },
+      distribution: base.dependency.distribution.clone(),
+      arch: base.dependency.arch.clone(),
+      registry: base.dependency.registry.clone()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use yaml_rust::YamlLoader;
+  use crate::registry::source::RegistrySource;
+  use crate::registry::{RegistrySpec, SourceKind, DEFAULT_REGISTRY_NAME};
+  use crate::types::Distribution;
+  use super::*;
+
+  type Versions<'a> = &'a [(&'a str, &'a [(&'a str, &'a str)])];
+
+  /// Index yaml of a sources package from its versions and their `(name, requirement)` dependencies.
+  fn index(name: &str, versions: Versions) -> String
+  {
+    let mut yaml = String::new();
+    for (version, dependencies) in versions {
+      yaml.push_str(&format!("{}:\n  source: file:///{}-{}.tar.gz\n", version, name, version));
+      if !dependencies.is_empty() {
+        yaml.push_str("  dependencies:\n");
+      }
+      for (dependency, requirement) in *dependencies {
+        yaml.push_str(&format!("    - {}:\n        version: \"{}\"\n        distribution: sources\n", dependency, requirement));
+      }
+    }
+    yaml
+  }
+
+  /// A registry of in-memory packages, without any checkout.
+  fn registry(packages: &[(&str, Versions)]) -> Registry
+  {
+    let spec = RegistrySpec
+    {
+      name: DEFAULT_REGISTRY_NAME.to_string(),
+      kind: SourceKind::Local { path: String::new() },
+      priority: 0
+    };
+    let mut source = RegistrySource::new(spec, "");
+    for (name, versions) in packages {
+      let yaml = YamlLoader::load_from_str(&index(name, versions)).unwrap();
+      source.index.insert(name.to_string(), Registry::parse_yaml(&yaml).unwrap());
+    }
+    Registry
+    {
+      sources: vec![source],
+      ..Registry::default()
+    }
+  }
+
+  /// Name, version and depth of every resolved package.
+  fn resolve(registry: &Registry, direct: &[(&str, &str)]) -> Result<Vec<(String, String, usize)>, Error>
+  {
+    let direct = direct
+      .iter()
+      .map(|(name, version)| (name.to_string(), Dependency
+      {
+        version: VersionReq::try_from(*version).unwrap(),
+        distribution: Distribution::Sources,
+        ..Dependency::default()
+      }))
+      .collect::<HashMap<String, Dependency>>();
+    Ok(Resolver::new(registry, "app")
+      .resolve(&direct)?
+      .into_iter()
+      .map(|dep| (dep.name, dep.version.to_string(), dep.depth))
+      .collect())
+  }
+
+  fn package(name: &str, version: &str, depth: usize) -> (String, String, usize)
+  {
+    (name.to_string(), version.to_string(), depth)
+  }
+
+  const LIBC: Versions = &[("1.0.0", &[]), ("2.0.0", &[])];
+
+  #[test]
+  fn backtracks_to_older_version()
+  {
+    // the newest liba wants libc 2, which libb rules out
+    let registry = registry(&[
+      ("liba", &[("1.0.0", &[("libc", "^1.0")]), ("2.0.0", &[("libc", "^2.0")])]),
+      ("libb", &[("1.0.0", &[("libc", "^1.0")])]),
+      ("libc", LIBC)
+    ]);
+    assert_eq!(resolve(&registry, &[("liba", ">=1.0.0"), ("libb", ">=1.0.0")]).unwrap(), vec![
+      package("liba", "1.0.0", 1),
+      package("libb", "1.0.0", 1),
+      package("libc", "1.0.0", 2)
+    ]);
+  }
+
+  #[test]
+  fn reports_conflicts()
+  {
+    let registry = registry(&[
+      ("liba", &[("2.0.0", &[("libc", "^2.0")])]),
+      ("libb", &[("1.0.0", &[("libc", "^1.0")])]),
+      ("libc", LIBC)
+    ]);
+    let error = resolve(&registry, &[("liba", ">=1.0.0"), ("libb", ">=1.0.0")]).unwrap_err().to_string();
+    assert!(error.starts_with("conflicting requirements for package libc"), "{}", error);
+    assert!(resolve(&registry, &[("libc", ">=3.0.0")]).is_err());
+  }
+
+  #[test]
+  fn detects_cycles()
+  {
+    let registry = registry(&[
+      ("liba", &[("1.0.0", &[("libb", "^1.0")])]),
+      ("libb", &[("1.0.0", &[("liba", "^1.0")])])
+    ]);
+    assert_eq!(resolve(&registry, &[("liba", "^1.0")]).unwrap_err().to_string(),
+      "dependency cycle detected: liba 1.0.0 -> libb 1.0.0 -> liba 1.0.0"
+    );
+  }
+
+  #[test]
+  fn depth_is_longest_path()
+  {
+    // libb is also required by liba, so it goes below it, and libc below libb
+    let registry = registry(&[
+      ("liba", &[("1.0.0", &[("libb", "^1.0")])]),
+      ("libb", &[("1.0.0", &[("libc", "^1.0")])]),
+      ("libc", LIBC)
+    ]);
+    assert_eq!(resolve(&registry, &[("liba", "^1.0"), ("libb", "^1.0")]).unwrap(), vec![
+      package("liba", "1.0.0", 1),
+      package("libb", "1.0.0", 2),
+      package("libc", "1.0.0", 3)
+    ]);
+  }
+}
@@ -0,0 +1,277 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::path::Path;
+use anyhow::{anyhow, bail, Context, ensure, Error};
+use colored::Colorize;
+use url::Url;
+use walkdir::WalkDir;
+use yaml_rust::Yaml;
+use crate::{log, warn};
+use crate::registry::client_builder::ClientConfig;
+use crate::registry::git::DEFAULT_BRANCH;
+use crate::registry::Registry;
+use crate::registry::sparse::{SparseClient, REGISTRY_CONFIG_NAME, SPARSE_PREFIX};
+use crate::types::RegistryIndex;
+
+pub const DEFAULT_REGISTRY_NAME: &str = "default";
+const REQUIRE_CHECKSUMS_KEY: &str = "require-checksums";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceKind
+{
+  Git { url: String, reference: Option<String> },
+  Local { path: String },
+  Sparse { url: String }
+}
+
+/// A registry as configured by the user, before anything is fetched.
+#[derive(Debug, Clone)]
+pub struct RegistrySpec
+{
+  pub name: String,
+  pub kind: SourceKind,
+  pub priority: i32
+}
+
+/// One named registry with its own checkout directory and index.
+#[derive(Debug)]
+pub struct RegistrySource
+{
+  pub spec: RegistrySpec,
+  pub path: String,
+  pub commit: Option<String>,
+  pub index: HashMap<String, RegistryIndex>,
+  pub config: Yaml,
+  sparse: Option<SparseClient>,
+  missing: HashSet<String>
+}
+
+impl Display for SourceKind
+{
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+  {
+    match self
+    {
+      Self::Git { url, reference } => write!(f, "{} ({})", url, reference.as_deref().unwrap_or(DEFAULT_BRANCH)),
+      Self::Local { path } => write!(f, "{} (local)", path),
+      Self::Sparse { url } => write!(f, "{} (sparse)", url)
+    }
+  }
+}
+
+impl SourceKind
+{
+  /// Remote registry from a url: `sparse+http(s)://...` selects the http index protocol,
+  /// anything else is treated as a git repository.
+  pub fn remote(url: &str, reference: Option<String>) -> Self
+  {
+    if url.starts_with(SPARSE_PREFIX) {
+      Self::Sparse { url: url.to_string() }
+    } else {
+      Self::Git { url: url.to_string(), reference }
+    }
+  }
+}
+
+impl RegistrySpec
+{
+  pub fn reference(&self) -> &str
+  {
+    match &self.kind
+    {
+      SourceKind::Git { reference, .. } => reference.as_deref().unwrap_or(DEFAULT_BRANCH),
+      SourceKind::Local { .. } => "local",
+      SourceKind::Sparse { .. } => "sparse"
+    }
+  }
+}
+
+impl RegistrySource
+{
+  pub fn new(spec: RegistrySpec, root: &str) -> Self
+  {
+    let path = Path::new(root)
+      .join(&spec.name)
+      .into_os_string()
+      .into_string()
+      .expect("os string should be convertible to string");
+    Self
+    {
+      spec,
+      path,
+      commit: None,
+      index: HashMap::new(),
+      config: Yaml::Null,
+      sparse: None,
+      missing: HashSet::new()
+    }
+  }
+
+  pub fn name(&self) -> &str
+  {
+    self.spec.name.as_str()
+  }
+
+  pub fn init(&mut self, offline: bool, client: &reqwest::Client, config: &ClientConfig) -> Result<(), Error>
+  {
+    log!("initializing registry {} at: {}", self.name().yellow().bold(), self.path);
+    match self.spec.kind.clone() {
+      SourceKind::Local { path } => self.init_local(path.as_str())?,
+      SourceKind::Git { url, .. } if offline => {
+        ensure!(self.seek().is_ok(),
+          "registry {} ({}) has never been fetched and cannot be used in offline mode",
+          self.name(),
+          url
+        );
+        warn!("offline mode: using existing checkout of registry {}", self.name());
+        self.commit = crate::registry::git::head_commit(self.path.as_str()).ok();
+      },
+      SourceKind::Git { url, .. } => match self.seek() {
+        Ok(_) => {
+          log!("found existing registry");
+          log!("updating registry from: {}", url);
+          self.commit = Some(crate::registry::git::update_repo(&url, self.path.as_str(), self.spec.reference(), config)?);
+        },
+        Err(e) => {
+          log!("{}", e);
+          log!("creating registry folder");
+          std::fs::create_dir_all(self.path.as_str())?;
+          log!("cloning registry from: {}", url);
+          self.commit = Some(crate::registry::git::clone_repo(&url, self.path.as_str(), self.spec.reference(), config)?);
+        }
+      },
+      SourceKind::Sparse { url } => {
+        log!("using sparse registry at: {}", url);
+        let client = SparseClient::new(&url, self.path.as_str(), offline, client.clone(), config.read_timeout)?;
+        let config = client
+          .config()?
+          .with_context(|| format!("{} is not an fdm registry: no {} file found", url, REGISTRY_CONFIG_NAME))?;
+        self.config = Self::parse_config(config.as_str())?;
+        self.sparse = Some(client);
+        // package files are fetched on demand by load_package
+        return Ok(());
+      }
+    };
+    let config_path = Path::new(self.path.as_str()).join(REGISTRY_CONFIG_NAME);
+    if config_path.is_file() {
+      self.config = Self::parse_config(std::fs::read_to_string(config_path)?.as_str())?;
+    }
+    for yaml in self.collect_yaml()? {
+      let index = Registry::parse_yaml(&yaml.1)
+        .with_context(|| format!("failed to parse registry entry {}", yaml.0))?;
+      self.index.insert(yaml.0, index);
+    }
+    Ok(())
+  }
+
+  // reads the index straight from a local directory or git repository, without network access
+  fn init_local(&mut self, local: &str) -> Result<(), Error>
+  {
+    let local = match Url::parse(local) {
+      Ok(url) if url.scheme() == "file" => url
+        .to_file_path()
+        .map_err(|_| anyhow!("invalid local registry url: {}", local))?
+        .into_os_string()
+        .into_string()
+        .expect("os string should be convertible to string"),
+      _ => local.to_string()
+    };
+    log!("loading local registry from: {}", local);
+    ensure!(Path::new(local.as_str()).is_dir(), "local registry not found at: {}", local);
+    match git2::Repository::open(local.as_str()) {
+      Ok(repo) if repo.is_bare() => {
+        log!("local registry is a bare git repository");
+        crate::registry::git::clone_local(local.as_str(), self.path.as_str())?;
+      },
+      _ => self.path = local
+    };
+    self.commit = crate::registry::git::head_commit(self.path.as_str()).ok();
+    Ok(())
+  }
+
+  /// Whether artifacts without a `sha256` are rejected instead of downloaded with a warning.
+  pub fn requires_checksums(&self) -> bool
+  {
+    self.config[REQUIRE_CHECKSUMS_KEY]
+      .as_bool()
+      .unwrap_or(false)
+  }
+
+  fn parse_config(content: &str) -> Result<Yaml, Error>
+  {
+    Ok(yaml_rust::YamlLoader::load_from_str(content)
+      .context("failed to parse registry config")?
+      .into_iter()
+      .next()
+      .unwrap_or(Yaml::Null))
+  }
+
+  /// Makes sure the package is in the index if this registry has it. Only sparse
+  /// registries actually fetch anything here; the others are fully loaded on init.
+  pub fn load_package(&mut self, name: &str) -> Result<bool, Error>
+  {
+    if self.index.contains_key(name) {
+      return Ok(true);
+    }
+    let Some(sparse) = &self.sparse else {
+      return Ok(false);
+    };
+    if self.missing.contains(name) {
+      return Ok(false);
+    }
+    match sparse.package(name)? {
+      Some(content) => {
+        let yaml = yaml_rust::YamlLoader::load_from_str(content.as_str())?;
+        let index = Registry::parse_yaml(&yaml)
+          .with_context(|| format!("failed to parse registry entry {}", name))?;
+        self.index.insert(name.to_string(), index);
+        Ok(true)
+      },
+      None => {
+        self.missing.insert(name.to_string());
+        Ok(false)
+      }
+    }
+  }
+
+  fn seek(&self) -> Result<(), Error>
+  {
+    if !Path::new(self.path.as_str()).exists() {
+      bail!("registry not found at: {}", self.path);
+    }
+    if std::fs::read_dir(self.path.as_str())?.next().is_none() {
+      bail!("registry is empty at: {}", self.path);
+    }
+    Ok(())
+  }
+
+  fn collect_yaml(&self) -> Result<Vec<(String, Vec<Yaml>)>, Error>
+  {
+    log!("collecting yaml files from registry");
+    let mut yaml_files = vec![];
+    for entry in WalkDir::new(self.path.as_str())
+      .into_iter()
+      .filter_entry(|e| e.file_name() != ".git")
+      .filter_map(|e| e.ok())
+      .filter(|e| e.file_type().is_file()
+        && e.path().extension().is_some()
+        && e.path().extension().unwrap() == "yml"
+      )
+    {
+      let content = std::fs::read_to_string(entry.path())?;
+      let yaml = yaml_rust::YamlLoader::load_from_str(content.as_str())?;
+      yaml_files.push((
+        entry
+          .path()
+          .file_stem()
+          .context("file stem is none")?
+          .to_os_string()
+          .into_string()
+          .expect("os string to string should not fail"),
+        yaml
+      ));
+    }
+    log!("found {} yaml files", yaml_files.len());
+    Ok(yaml_files)
+  }
+}
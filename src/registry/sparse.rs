@@ -0,0 +1,242 @@
+use std::future::Future;
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{anyhow, bail, ensure, Context, Error};
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use url::Url;
+use crate::log;
+use crate::registry::auth::CREDENTIALS;
+
+pub const SPARSE_PREFIX: &str = "sparse+";
+pub const REGISTRY_CONFIG_NAME: &str = "config";
+
+/// Fetches single files of a registry served over plain http(s), caching them on disk
+/// together with their `ETag` / `Last-Modified` validators.
+#[derive(Debug)]
+pub struct SparseClient
+{
+  base: Url,
+  cache_dir: String,
+  offline: bool,
+  client: reqwest::Client,
+  read_timeout: Option<Duration>,
+  runtime: tokio::runtime::Runtime
+}
+
+impl SparseClient
+{
+  pub fn new(base: &str,
+             cache_dir: &str,
+             offline: bool,
+             client: reqwest::Client,
+             read_timeout: Option<Duration>) -> Result<Self, Error>
+  {
+    let base = base.strip_prefix(SPARSE_PREFIX).unwrap_or(base);
+    let base = if base.ends_with('/') {
+      base.to_string()
+    } else {
+      format!("{}/", base)
+    };
+    std::fs::create_dir_all(cache_dir)?;
+    Ok(Self
+    {
+      base: Url::parse(base.as_str()).with_context(|| format!("invalid sparse registry url: {}", base))?,
+      cache_dir: cache_dir.to_string(),
+      offline,
+      client,
+      read_timeout,
+      runtime: tokio::runtime::Runtime::new()?
+    })
+  }
+
+  pub fn config(&self) -> Result<Option<String>, Error>
+  {
+    self.fetch(REGISTRY_CONFIG_NAME)
+  }
+
+  /// Returns the descriptor file of a package, or `None` if the registry does not have it.
+  pub fn package(&self, name: &str) -> Result<Option<String>, Error>
+  {
+    ensure!(!name.is_empty() && name
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+      && !name.starts_with('.'),
+      "invalid package name: {}", name
+    );
+    self.fetch(format!("{}.yml", name).as_str())
+  }
+
+  fn fetch(&self, file: &str) -> Result<Option<String>, Error>
+  {
+    let cache_path = Path::new(&self.cache_dir).join(file);
+    let etag_path = Path::new(&self.cache_dir).join(format!("{}.etag", file));
+    let modified_path = Path::new(&self.cache_dir).join(format!("{}.last-modified", file));
+    let cached = std::fs::read_to_string(&cache_path).ok();
+    if self.offline {
+      return Ok(cached);
+    }
+    let url = self.base.join(file)?;
+    let mut request = CREDENTIALS.authorize(self.client.get(url.as_str()), &url);
+    if cached.is_some() {
+      if let Ok(etag) = std::fs::read_to_string(&etag_path) {
+        request = request.header(IF_NONE_MATCH, etag);
+      }
+      if let Ok(modified) = std::fs::read_to_string(&modified_path) {
+        request = request.header(IF_MODIFIED_SINCE, modified);
+      }
+    }
+    let response = self.block_on(&url, request.send())?
+      .with_context(|| format!("failed to fetch {}", url))?;
+    match response.status() {
+      StatusCode::NOT_MODIFIED if cached.is_some() => Ok(cached),
+      StatusCode::NOT_FOUND | StatusCode::GONE => {
+        for path in [&cache_path, &etag_path, &modified_path] {
+          if path.exists() {
+            std::fs::remove_file(path)?;
+          }
+        }
+        Ok(None)
+      },
+      status if status.is_success() => {
+        let etag = response.headers().get(ETAG).and_then(|v| v.to_str().ok()).map(String::from);
+        let modified = response.headers().get(LAST_MODIFIED).and_then(|v| v.to_str().ok()).map(String::from);
+        let body = self.block_on(&url, response.text())??;
+        log!("fetched {}", url);
+        std::fs::write(&cache_path, &body)?;
+        for (path, value) in [(&etag_path, etag), (&modified_path, modified)] {
+          match value {
+            Some(value) => std::fs::write(path, value)?,
+            None if path.exists() => std::fs::remove_file(path)?,
+            None => {}
+          }
+        }
+        Ok(Some(body))
+      },
+      status => bail!("failed to fetch {}: status code {}", url, status.as_str())
+    }
+  }
+
+  /// Runs `future` to completion, giving up once the read timeout passes.
+  fn block_on<F: Future>(&self, url: &Url, future: F) -> Result<F::Output, Error>
+  {
+    match self.read_timeout {
+      Some(timeout) => self.runtime
+        .block_on(async { tokio::time::timeout(timeout, future).await })
+        .map_err(|_| anyhow!("failed to fetch {}: no data received for {} seconds", url, timeout.as_secs())),
+      None => Ok(self.runtime.block_on(future))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use std::io::{BufRead, BufReader, Write};
+  use std::net::TcpListener;
+  use std::path::PathBuf;
+  use std::sync::{Arc, Mutex};
+  use super::*;
+
+  const ETAG_VALUE: &str = "\"v1\"";
+  const MODIFIED_VALUE: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+  const PACKAGE: &str = "1.0.0:\n  source: http://localhost/liba.tar.gz\n";
+
+  /// Serves `config` and `liba.yml` (with validators), 404 for anything else. Returns the
+  /// base url and the heads of all requests, lowercased.
+  fn serve() -> (String, Arc<Mutex<Vec<String>>>)
+  {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}/", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let seen = requests.clone();
+    std::thread::spawn(move || {
+      for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        let mut head = String::new();
+        let mut reader = BufReader::new(&stream);
+        loop {
+          let mut line = String::new();
+          if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+            break;
+          }
+          head.push_str(&line.to_ascii_lowercase());
+        }
+        let path = head.split_whitespace().nth(1).unwrap_or_default().to_string();
+        let (status, headers, body) = match path.as_str() {
+          "/config" => ("200 OK", String::new(), "name: test\n"),
+          "/liba.yml" if head.contains(&format!("if-none-match: {}", ETAG_VALUE)) => ("304 Not Modified", String::new(), ""),
+          "/liba.yml" => ("200 OK", format!("ETag: {}\r\nLast-Modified: {}\r\n", ETAG_VALUE, MODIFIED_VALUE), PACKAGE),
+          _ => ("404 Not Found", String::new(), "")
+        };
+        seen.lock().unwrap().push(head);
+        write!(stream, "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+          status,
+          headers,
+          body.len(),
+          body
+        ).unwrap();
+      }
+    });
+    (base, requests)
+  }
+
+  fn cache_dir(name: &str) -> PathBuf
+  {
+    let dir = std::env::temp_dir().join(format!("fdm-sparse-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+  }
+
+  fn client(base: &str, cache_dir: &Path, offline: bool) -> SparseClient
+  {
+    SparseClient::new(base, cache_dir.to_str().unwrap(), offline, reqwest::Client::new(), Some(Duration::from_secs(5))).unwrap()
+  }
+
+  #[test]
+  fn revalidates_cached_files()
+  {
+    let (base, requests) = serve();
+    let dir = cache_dir("revalidate");
+    let sparse = client(format!("{}{}", SPARSE_PREFIX, base).as_str(), &dir, false);
+    assert_eq!(sparse.config().unwrap().as_deref(), Some("name: test\n"));
+    assert_eq!(sparse.package("liba").unwrap().as_deref(), Some(PACKAGE));
+    assert_eq!(std::fs::read_to_string(dir.join("liba.yml.etag")).unwrap(), ETAG_VALUE);
+    // the second fetch is answered with 304 and served from the cache
+    assert_eq!(sparse.package("liba").unwrap().as_deref(), Some(PACKAGE));
+    let requests = requests.lock().unwrap();
+    assert!(!requests[1].contains("if-none-match"));
+    assert!(requests[2].contains(&format!("if-none-match: {}", ETAG_VALUE)));
+    assert!(requests[2].contains(&format!("if-modified-since: {}", MODIFIED_VALUE.to_ascii_lowercase())));
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn missing_package_is_none()
+  {
+    let (base, _) = serve();
+    let dir = cache_dir("missing");
+    let sparse = client(&base, &dir, false);
+    std::fs::write(dir.join("gone.yml"), "1.0.0:\n").unwrap();
+    std::fs::write(dir.join("gone.yml.etag"), "\"old\"").unwrap();
+    assert_eq!(sparse.package("gone").unwrap(), None);
+    assert!(!dir.join("gone.yml").exists());
+    assert!(!dir.join("gone.yml.etag").exists());
+    assert!(sparse.package("../config").is_err());
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn offline_reads_the_cache()
+  {
+    let (base, requests) = serve();
+    let dir = cache_dir("offline");
+    client(&base, &dir, false).package("liba").unwrap();
+    let fetched = requests.lock().unwrap().len();
+    let offline = client(&base, &dir, true);
+    assert_eq!(offline.package("liba").unwrap().as_deref(), Some(PACKAGE));
+    assert_eq!(offline.package("libb").unwrap(), None);
+    assert_eq!(requests.lock().unwrap().len(), fetched);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,269 @@
+use std::fmt::Display;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use anyhow::{bail, ensure, Context, Error};
+use colored::Colorize;
+use decompress::{Decompressor, ExtractOptsBuilder};
+use decompress::decompressors::{tarball::Tarball, tarbz::Tarbz, targz::Targz, tarxz::Tarxz, tarzst::Tarzst, zip::Zip};
+use crate::warn;
+
+// enough for the ustar magic at offset 257
+const MAGIC_LENGTH: usize = 512;
+
+/// How an archive maps onto its package directory. `strip` leading path components are
+/// removed, then only `subdir` is kept. Without either, a single top-level directory is stripped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtractLayout
+{
+  pub strip: Option<usize>,
+  pub subdir: Option<String>
+}
+
+impl ExtractLayout
+{
+  pub fn new(strip: Option<usize>, subdir: Option<String>) -> Result<Self, Error>
+  {
+    if let Some(subdir) = &subdir {
+      ensure!(!subdir.is_empty() && Path::new(subdir)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_))),
+        "subdir must be a relative path inside the archive, got: {}", subdir
+      );
+    }
+    Ok(Self { strip, subdir })
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat
+{
+  TarGz,
+  TarXz,
+  TarBz2,
+  TarZst,
+  Tar,
+  Zip
+}
+
+impl Display for ArchiveFormat
+{
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+  {
+    write!(f, "{}", self.extension())
+  }
+}
+
+impl ArchiveFormat
+{
+  pub const ALL: [Self; 6] = [Self::TarGz, Self::TarXz, Self::TarBz2, Self::TarZst, Self::Tar, Self::Zip];
+
+  pub fn extension(&self) -> &'static str
+  {
+    match self
+    {
+      Self::TarGz => "tar.gz",
+      Self::TarXz => "tar.xz",
+      Self::TarBz2 => "tar.bz2",
+      Self::TarZst => "tar.zst",
+      Self::Tar => "tar",
+      Self::Zip => "zip"
+    }
+  }
+
+  /// Format by file name or url path suffix.
+  pub fn from_name(name: &str) -> Option<Self>
+  {
+    let name = name.to_ascii_lowercase();
+    let suffixes: [(&[&str], Self); 6] = [
+      (&[".tar.gz", ".tgz"], Self::TarGz),
+      (&[".tar.xz", ".txz"], Self::TarXz),
+      (&[".tar.bz2", ".tbz2", ".tbz"], Self::TarBz2),
+      (&[".tar.zst", ".tzst"], Self::TarZst),
+      (&[".tar"], Self::Tar),
+      (&[".zip"], Self::Zip)
+    ];
+    suffixes
+      .into_iter()
+      .find(|(suffixes, _)| suffixes.iter().any(|suffix| name.ends_with(suffix)))
+      .map(|(_, format)| format)
+  }
+
+  pub fn from_content_type(content_type: &str) -> Option<Self>
+  {
+    let mime = content_type
+      .split(';')
+      .next()
+      .unwrap_or_default()
+      .trim()
+      .to_ascii_lowercase();
+    match mime.as_str() {
+      "application/gzip" | "application/x-gzip" | "application/x-tgz" => Some(Self::TarGz),
+      "application/x-xz" => Some(Self::TarXz),
+      "application/x-bzip2" | "application/x-bzip" => Some(Self::TarBz2),
+      "application/zstd" | "application/x-zstd" => Some(Self::TarZst),
+      "application/x-tar" => Some(Self::Tar),
+      "application/zip" | "application/x-zip-compressed" => Some(Self::Zip),
+      _ => None
+    }
+  }
+
+  pub fn from_magic(bytes: &[u8]) -> Option<Self>
+  {
+    match bytes {
+      [0x1f, 0x8b, ..] => Some(Self::TarGz),
+      [0xfd, b'7', b'z', b'X', b'Z', 0x00, ..] => Some(Self::TarXz),
+      [b'B', b'Z', b'h', ..] => Some(Self::TarBz2),
+      [0x28, 0xb5, 0x2f, 0xfd, ..] => Some(Self::TarZst),
+      [b'P', b'K', 0x03, 0x04, ..] | [b'P', b'K', 0x05, 0x06, ..] => Some(Self::Zip),
+      _ if bytes.len() >= 262 && &bytes[257..262] == b"ustar" => Some(Self::Tar),
+      _ => None
+    }
+  }
+
+  /// Detects the format of the file at `path`. The content decides; `names` (file names or
+  /// url paths) and the `Content-Type` only matter for what has no magic bytes.
+  pub fn detect(path: &Path, names: &[&str], content_type: Option<&str>) -> Result<Self, Error>
+  {
+    let mut head = Vec::with_capacity(MAGIC_LENGTH);
+    std::fs::File::open(path)
+      .with_context(|| format!("failed to open archive {}", path.display()))?
+      .take(MAGIC_LENGTH as u64)
+      .read_to_end(&mut head)?;
+    let declared = names
+      .iter()
+      .find_map(|name| Self::from_name(name))
+      .or_else(|| content_type.and_then(Self::from_content_type));
+    match (Self::from_magic(&head), declared) {
+      (Some(detected), Some(declared)) if detected != declared => {
+        warn!("{} is named or served as {} but contains {}", names.first().unwrap_or(&""), declared, detected);
+        Ok(detected)
+      },
+      (Some(detected), _) => Ok(detected),
+      // old tar archives have no magic
+      (None, Some(Self::Tar)) => Ok(Self::Tar),
+      (None, _) => bail!("unsupported archive format: {} (content type: {}). supported formats: {}",
+        names.first().unwrap_or(&""),
+        content_type.unwrap_or("unknown"),
+        Self::ALL
+          .iter()
+          .map(|format| format.extension())
+          .collect::<Vec<&str>>()
+          .join(", ")
+      )
+    }
+  }
+
+  /// Unpacks into `to` following `layout`. The archive is unpacked next to `to` first, so
+  /// `to` is only replaced by a complete package and no files of what was there before survive.
+  pub fn extract(&self, from: &Path, to: &Path, layout: &ExtractLayout) -> Result<(), Error>
+  {
+    let staging = Self::staging_path(to)?;
+    if staging.exists() {
+      std::fs::remove_dir_all(&staging)?;
+    }
+    std::fs::create_dir_all(&staging)?;
+    let result = self
+      .unpack(from, &staging, layout.strip.unwrap_or(0))
+      .and_then(|_| Self::layout_root(&staging, layout))
+      .and_then(|root| {
+        if to.exists() {
+          std::fs::remove_dir_all(to)?;
+        }
+        std::fs::rename(&root, to)
+          .with_context(|| format!("failed to move {} to {}", root.display(), to.display()))
+      });
+    if staging.exists() {
+      std::fs::remove_dir_all(&staging)?;
+    }
+    result
+  }
+
+  /// Unpacks with the decompressor for this format, whatever the file is named.
+  pub fn unpack(&self, from: &Path, to: &Path, strip: usize) -> Result<(), Error>
+  {
+    let decompressor: Box<dyn Decompressor> = match self
+    {
+      Self::TarGz => Box::<Targz>::default(),
+      Self::TarXz => Box::<Tarxz>::default(),
+      Self::TarBz2 => Box::<Tarbz>::default(),
+      Self::TarZst => Box::<Tarzst>::default(),
+      Self::Tar => Box::<Tarball>::default(),
+      Self::Zip => Box::<Zip>::default()
+    };
+    decompressor
+      .decompress(from, to, &ExtractOptsBuilder::default().strip(strip).build()?)
+      .with_context(|| format!("failed to unpack {} archive {}", self, from.display()))?;
+    Ok(())
+  }
+
+  fn staging_path(to: &Path) -> Result<PathBuf, Error>
+  {
+    let name = to
+      .file_name()
+      .with_context(|| format!("invalid unpack target {}", to.display()))?;
+    Ok(to.with_file_name(format!(".{}.unpack", name.to_string_lossy())))
+  }
+
+  /// The directory inside the unpacked archive that becomes the package.
+  fn layout_root(unpacked: &Path, layout: &ExtractLayout) -> Result<PathBuf, Error>
+  {
+    let root = match (layout.strip, &layout.subdir) {
+      (None, None) => {
+        let entries = std::fs::read_dir(unpacked)?.collect::<Result<Vec<_>, _>>()?;
+        match entries.as_slice() {
+          [single] if single.file_type()?.is_dir() => single.path(),
+          _ => unpacked.to_path_buf()
+        }
+      },
+      (_, Some(subdir)) => unpacked.join(subdir),
+      (Some(_), None) => unpacked.to_path_buf()
+    };
+    ensure!(root.is_dir(), "archive has no directory {}", match &layout.subdir {
+      Some(subdir) => subdir.as_str(),
+      None => "to unpack"
+    });
+    Ok(root)
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  #[test]
+  fn content_decides_over_name()
+  {
+    let dir = std::env::temp_dir().join(format!("fdm-archive-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = |name: &str, content: &[u8]| {
+      let path = dir.join(name);
+      std::fs::write(&path, content).unwrap();
+      path
+    };
+    let zip = file("zip", b"PK\x03\x04rest");
+    assert_eq!(ArchiveFormat::detect(&zip, &["package.tar.gz"], None).unwrap(), ArchiveFormat::Zip);
+    assert_eq!(ArchiveFormat::detect(&zip, &[], Some("application/gzip")).unwrap(), ArchiveFormat::Zip);
+    let mut ustar = vec![0u8; 512];
+    ustar[257..262].copy_from_slice(b"ustar");
+    let tar = file("tar", &ustar);
+    assert_eq!(ArchiveFormat::detect(&tar, &["download"], None).unwrap(), ArchiveFormat::Tar);
+    // without magic bytes only a declared tar is believed
+    let unknown = file("unknown", &[0u8; 512]);
+    assert_eq!(ArchiveFormat::detect(&unknown, &["old.TAR"], None).unwrap(), ArchiveFormat::Tar);
+    assert_eq!(ArchiveFormat::detect(&unknown, &[], Some("application/x-tar; charset=binary")).unwrap(), ArchiveFormat::Tar);
+    assert!(ArchiveFormat::detect(&unknown, &["package.tgz"], None).is_err());
+    assert!(ArchiveFormat::detect(&unknown, &[], None).is_err());
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn names_and_content_types()
+  {
+    assert_eq!(ArchiveFormat::from_name("https://host/pkg-1.0.0.TGZ"), Some(ArchiveFormat::TarGz));
+    assert_eq!(ArchiveFormat::from_name("pkg.tar.zst"), Some(ArchiveFormat::TarZst));
+    assert_eq!(ArchiveFormat::from_name("pkg.tar.gz.sig"), None);
+    assert_eq!(ArchiveFormat::from_content_type("application/x-xz"), Some(ArchiveFormat::TarXz));
+    assert_eq!(ArchiveFormat::from_content_type("application/octet-stream"), None);
+  }
+}
@@ -2,22 +2,45 @@ use futures_util::stream::StreamExt;
 use std::fmt::Display;
 use std::fs::File;
 use std::io::Write;
-use std::path::{Path};
-use anyhow::{ensure, Error};
+use std::path::{Path, PathBuf};
+use std::future::Future;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, ensure, Error};
 use colored::Colorize;
-use decompress::{ExtractOptsBuilder};
-use indicatif::{ProgressBar, ProgressDrawTarget};
-use crate::config::wd;
-use crate::consts::{FDM_CACHE_NAME, FDM_DIRECTORY_NAME, FDM_LIBS_NAME, FDM_PACK_NAME};
-use crate::{log};
-use crate::types::{Distribution, PlatformArch, Version};
+use indicatif::{MultiProgress, ProgressBar};
+use sha2::{Digest, Sha256};
+use crate::config::CONFIG;
+use crate::cache::ArchiveCache;
+use crate::manifest::InstallState;
+use crate::registry::auth::CREDENTIALS;
+use crate::{log, warn};
+use reqwest::header::{CONTENT_TYPE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE};
+use reqwest::StatusCode;
+use url::Url;
+use crate::types::{ArchiveFormat, Distribution, ExtractLayout, PlatformArch, Version, VersionReq};
 
 #[derive(Debug, Clone)]
 pub struct Dependency
 {
+  pub version: VersionReq,
+  pub distribution: Distribution,
+  pub arch: Option<PlatformArch>,
+  pub registry: Option<String>
+}
+
+#[derive(Debug, Clone)]
+pub struct ResolvedDependency
+{
+  pub name: String,
   pub version: Version,
   pub distribution: Distribution,
-  pub arch: Option<PlatformArch>
+  pub arch: PlatformArch,
+  pub url: Url,
+  pub mirrors: Vec<Url>,
+  pub checksum: Option<String>,
+  pub layout: ExtractLayout,
+  pub depth: usize,
+  pub registry: String
 }
 
 impl Default for Dependency
@@ -26,9 +49,10 @@ impl Default for Dependency
   {
     Self
     {
-      version: Version::default(),
+      version: VersionReq::default(),
       distribution: Distribution::default(),
-      arch: None
+      arch: None,
+      registry: None
     }
   }
 }
@@ -44,36 +68,200 @@ impl Display for Dependency
   }
 }
 
-impl Dependency
+impl Display for ResolvedDependency
+{
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+  {
+    write!(f, "{} {} ({}/{})",
+           self.name,
+           self.version,
+           self.distribution,
+           self.arch)
+  }
+}
+
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// A download failure worth retrying: dropped connections, timeouts, 5xx and 429 responses.
+#[derive(Debug)]
+struct TransientError(String);
+
+impl Display for TransientError
+{
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+  {
+    write!(f, "{}", self.0)
+  }
+}
+
+impl std::error::Error for TransientError {}
+
+impl TransientError
+{
+  fn classify(e: reqwest::Error) -> Error
+  {
+    if e.is_timeout() || e.is_connect() || e.is_request() || e.is_body() {
+      Self(e.to_string()).into()
+    } else {
+      e.into()
+    }
+  }
+
+  /// Runs `future`, failing transiently when it takes longer than `timeout`.
+  async fn within<F: Future>(timeout: Option<Duration>, future: F) -> Result<F::Output, Error>
+  {
+    match timeout {
+      Some(timeout) => tokio::time::timeout(timeout, future)
+        .await
+        .map_err(|_| Self(format!("no data received for {} seconds", timeout.as_secs())).into()),
+      None => Ok(future.await)
+    }
+  }
+}
+
+impl ResolvedDependency
 {
-  pub async fn download_from_registry(&self, name: &str) -> Result<(), Error>
+  /// Downloads and unpacks the archive, returning its sha256 checksum.
+  pub async fn download_from_registry(&self, client: &reqwest::Client, progress: &MultiProgress) -> Result<String, Error>
   {
-    log!("downloading {} {}/{}/{}",
-      name.to_string().bright_blue().bold(),
-      self.version.to_string().bold(),
-      self.distribution.to_string().white().bold(),
-      self.arch.as_ref().unwrap_or(&PlatformArch::Any).to_string().white().italic()
+    let name = self.name.as_str();
+    // anything printed while the progress bars are drawn goes above them
+    progress.suspend(|| {
+      log!("downloading {} {}/{}/{}",
+        name.to_string().bright_blue().bold(),
+        self.version.to_string().bold(),
+        self.distribution.to_string().white().bold(),
+        self.arch.to_string().white().italic()
+      );
+    });
+    let (cache, offline, urls) = {
+      let cfg = CONFIG
+        .lock()
+        .unwrap();
+      let urls = std::iter::once(&self.url)
+        .chain(&self.mirrors)
+        .map(|url| cfg.rewrite_url(url))
+        .collect::<Vec<Url>>();
+      (ArchiveCache::new(cfg.cache_dir().as_str())?, cfg.is_offline(), urls)
+    };
+    // the cache entry of a package is keyed by its primary url, whichever mirror served it
+    let primary = &urls[0];
+    let _lock = cache.lock(primary, progress).await?;
+    let cached = match primary.scheme() {
+      "file" => None,
+      _ => cache.find(primary, self.checksum.as_deref())
+    };
+    let (archive, checksum) = match cached {
+      Some(cached) => {
+        progress.suspend(|| { log!("using cached archive for {}", name); });
+        let checksum = Self::hash_file(&cached).await?;
+        self.verify_checksum(&cached, checksum.as_str(), true)?;
+        (cached, checksum)
+      },
+      None => {
+        let mut fetched = None;
+        let mut errors = Vec::new();
+        for url in &urls {
+          match self.fetch(client, progress, &cache, primary, url, offline).await {
+            Ok(result) => {
+              fetched = Some(result);
+              break;
+            },
+            Err(e) => {
+              if urls.len() > 1 {
+                progress.suspend(|| { warn!("{:#}", e); });
+              }
+              errors.push(e);
+            }
+          }
+        }
+        match fetched {
+          Some(fetched) => fetched,
+          None if errors.len() == 1 => return Err(errors.remove(0)),
+          None => bail!("failed to get {} from any of its {} urls", name, urls.len())
+        }
+      }
+    };
+    let archive_name = archive
+      .file_name()
+      .map(|name| name.to_string_lossy().to_string())
+      .unwrap_or_default();
+    let format = progress.suspend(|| ArchiveFormat::detect(&archive, &[archive_name.as_str(), primary.path()], None))?;
+    progress.suspend(|| { log!("unpacking {} archive...", format); });
+    let target = InstallState::package_path(name)?;
+    let layout = self.layout.clone();
+    tokio::task::spawn_blocking(move || format.extract(&archive, &target, &layout)).await??;
+    Ok(checksum)
+  }
+
+  /// Gets the archive from one url: local files are used in place, anything else is
+  /// downloaded into the cache. Returns the archive path and its checksum.
+  async fn fetch(&self,
+                 client: &reqwest::Client,
+                 progress: &MultiProgress,
+                 cache: &ArchiveCache,
+                 primary: &Url,
+                 url: &Url,
+                 offline: bool) -> Result<(PathBuf, String), Error>
+  {
+    if url.scheme() == "file" {
+      let source = url
+        .to_file_path()
+        .map_err(|_| anyhow!("invalid file url: {}", url))?;
+      progress.suspend(|| { log!("using local archive {}", source.display()); });
+      let checksum = Self::hash_file(&source)
+        .await
+        .with_context(|| format!("failed to read local archive {}", source.display()))?;
+      self.verify_checksum(&source, checksum.as_str(), false)?;
+      return Ok((source, checksum));
+    }
+    ensure!(!offline,
+      "cannot download {} from {} in offline mode: only file:// urls and cached archives are available",
+      self.name,
+      url
     );
-    let path = self.cache_path(name);
-    if !Path::new(&path).exists() {
-      self.create_directory(name)?;
+    let partial = cache.partial_path(url);
+    let (checksum, content_type) = self.download(client, progress, &partial, url).await?;
+    self.verify_checksum(&partial, checksum.as_str(), true)?;
+    let format = match progress.suspend(|| ArchiveFormat::detect(&partial, &[url.path()], content_type.as_deref())) {
+      Ok(format) => format,
+      Err(e) => {
+        std::fs::remove_file(&partial)?;
+        return Err(e.context(format!("cannot use {} from {}", self.name, url)));
+      }
+    };
+    Ok((cache.store(primary, &partial, checksum.as_str(), format)?, checksum))
+  }
+
+  /// Fails on a checksum mismatch, deleting the file first if it is ours to delete.
+  fn verify_checksum(&self, path: &Path, checksum: &str, delete: bool) -> Result<(), Error>
+  {
+    if let Some(expected) = &self.checksum {
+      if expected != checksum {
+        if delete {
+          std::fs::remove_file(path)?;
+        }
+        bail!("checksum mismatch for {}: expected {}, got {}", self.name, expected, checksum);
+      }
     }
-    let registry = crate::registry::REGISTRY
-      .lock()
-      .unwrap();
-    let url = registry
-      .get(name, &self)?;
-
-    let response = registry.client
-      .get(url.as_str())
-      .send()
-      .await?;
-
-    ensure!(response.status().is_success(), format!("failed to download: status code {}", response.status().as_str()));
-    let total = response
-      .content_length()
-      .unwrap_or(1);
-    let pb = ProgressBar::new(total)
+    Ok(())
+  }
+
+  /// Returns the checksum and the `Content-Type` of the download.
+  async fn download(&self,
+                    client: &reqwest::Client,
+                    progress: &MultiProgress,
+                    part_path: &Path,
+                    url: &Url) -> Result<(String, Option<String>), Error>
+  {
+    let (attempts, read_timeout) = {
+      let cfg = CONFIG
+        .lock()
+        .unwrap();
+      (cfg.retries, cfg.client_config()?.read_timeout)
+    };
+    let pb = progress.add(ProgressBar::new(1)
       .with_style(
         indicatif::ProgressStyle::default_bar()
           .template("{wide_msg} {bytes_per_sec:8} {elapsed_precise:8} eta:{eta:3} {spinner:.green} \
@@ -82,66 +270,141 @@ impl Dependency
           )
           .expect("template should be valid")
           .progress_chars("█░░")
-      );
-    pb.set_draw_target(ProgressDrawTarget::stdout_with_hz(5));
-    pb.set_message(format!("downloading {}...", name));
-
-    let file_path = Path::new(&path)
-      .join("archive.tar.gz");
-    let mut file = File::create(&file_path)?;
-    let mut downloaded: u64 = 0;
-    let mut stream = response.bytes_stream();
-    while let Some(item) = stream.next().await {
-      let chunk = item?;
-      file.write_all(&chunk)?;
-      let new = (downloaded + chunk.len() as u64).min(total);
-      downloaded = new;
-      pb.set_position(new);
+      ));
+    pb.set_message(format!("downloading {}...", self.name));
+    let mut attempt = 1;
+    loop {
+      // unfinished downloads stay in the cache and are resumed on the next attempt or run
+      match self.download_attempt(client, &pb, part_path, url, read_timeout).await {
+        Ok(downloaded) => {
+          pb.finish_with_message("done!");
+          return Ok(downloaded);
+        },
+        Err(e) if attempt < attempts && e.downcast_ref::<TransientError>().is_some() => {
+          let delay = RETRY_BASE_DELAY
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(RETRY_MAX_DELAY);
+          progress.suspend(|| {
+            warn!("downloading {} failed ({}), retrying in {:.1}s (attempt {}/{})",
+              self.name,
+              e,
+              delay.as_secs_f32(),
+              attempt + 1,
+              attempts
+            );
+          });
+          tokio::time::sleep(delay).await;
+          attempt += 1;
+        },
+        Err(e) => {
+          pb.abandon_with_message(format!("downloading {} failed", self.name));
+          return Err(e.context(format!("failed to download {} from {}", self.name, url)));
+        }
+      }
     }
-    pb.finish_with_message("done!");
-    let target = Path::new(&wd()?)
-      .join(FDM_DIRECTORY_NAME)
-      .join(FDM_PACK_NAME)
-      .join(FDM_LIBS_NAME)
-      .join(name)
-      .into_os_string()
-      .into_string()
-      .expect("os string should be convertible to string");
-    Self::unpack(&file_path.to_str().expect("file path should be convertible to string"), &target)?;
-    Ok(())
   }
 
-  fn create_directory(&self, name: &str) -> Result<(), Error>
+  async fn download_attempt(&self,
+                            client: &reqwest::Client,
+                            pb: &ProgressBar,
+                            part_path: &Path,
+                            url: &Url,
+                            read_timeout: Option<Duration>) -> Result<(String, Option<String>), Error>
   {
-    let p = self.cache_path(name);
-    log!("creating cache directory at: ...{}", &p[p.len()-30..]);
-    std::fs::create_dir_all(&self.cache_path(name))?;
-    Ok(())
+    // a partial file is only resumed if the server can tell whether it still has the same file
+    let validator_path = ArchiveCache::validator_path(part_path);
+    let validator = std::fs::read_to_string(&validator_path).ok();
+    let offset = match validator {
+      Some(_) => std::fs::metadata(part_path)
+        .map(|meta| meta.len())
+        .unwrap_or(0),
+      None => 0
+    };
+    let mut request = CREDENTIALS.authorize(client.get(url.as_str()), url);
+    if let (true, Some(validator)) = (offset > 0, &validator) {
+      request = request
+        .header(RANGE, format!("bytes={}-", offset))
+        .header(IF_RANGE, validator.as_str());
+    }
+    let response = TransientError::within(read_timeout, request.send())
+      .await?
+      .map_err(TransientError::classify)?;
+    let status = response.status();
+    if status == StatusCode::RANGE_NOT_SATISFIABLE {
+      // the partial file does not belong to what the server has now
+      std::fs::remove_file(part_path)?;
+      return Err(TransientError(format!("cannot resume from byte {}", offset)).into());
+    }
+    if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+      return Err(TransientError(format!("status code {}", status.as_str())).into());
+    }
+    ensure!(status != StatusCode::UNAUTHORIZED && status != StatusCode::FORBIDDEN,
+      "access denied (status code {}), check the credentials for {}",
+      status.as_str(),
+      url.host_str().unwrap_or_default()
+    );
+    ensure!(status.is_success(), "status code {}", status.as_str());
+    let content_type = response
+      .headers()
+      .get(CONTENT_TYPE)
+      .and_then(|value| value.to_str().ok())
+      .map(String::from);
+
+    // a file that changed since is sent whole, so the download starts over
+    let resumed = offset > 0 && status == StatusCode::PARTIAL_CONTENT;
+    if !resumed {
+      let headers = response.headers();
+      let etag = headers
+        .get(ETAG)
+        .and_then(|value| value.to_str().ok())
+        .filter(|etag| !etag.starts_with("W/"));
+      match etag.or_else(|| headers.get(LAST_MODIFIED).and_then(|value| value.to_str().ok())) {
+        Some(validator) => std::fs::write(&validator_path, validator)?,
+        None if validator_path.exists() => std::fs::remove_file(&validator_path)?,
+        None => {}
+      }
+    }
+    let (mut file, mut hasher) = if resumed {
+      pb.suspend(|| { log!("resuming download of {} from byte {}", self.name, offset); });
+      let hasher = Self::hasher_of(part_path).await?;
+      (std::fs::OpenOptions::new()
+        .append(true)
+        .open(part_path)?, hasher)
+    } else {
+      (File::create(part_path)?, Sha256::new())
+    };
+    let mut downloaded = if resumed { offset } else { 0 };
+    pb.set_length(response
+      .content_length()
+      .map(|length| length + downloaded)
+      .unwrap_or(1)
+      .max(1)
+    );
+    pb.set_position(downloaded);
+    let mut stream = response.bytes_stream();
+    while let Some(item) = TransientError::within(read_timeout, stream.next()).await? {
+      let chunk = item.map_err(TransientError::classify)?;
+      file.write_all(&chunk)?;
+      hasher.update(&chunk);
+      downloaded += chunk.len() as u64;
+      pb.set_position(downloaded);
+    }
+    Ok((format!("{:x}", hasher.finalize()), content_type))
   }
 
-  fn unpack(from: &str, to: &str) -> Result<(), Error>
+  async fn hash_file(path: &Path) -> Result<String, Error>
   {
-    log!("unpacking...");
-    std::fs::create_dir_all(to)?;
-    let _file = std::fs::File::open(from)?;
-    decompress::decompress(from, to, &ExtractOptsBuilder::default().strip(1).build()?)?;
-    Ok(())
+    Ok(format!("{:x}", Self::hasher_of(path).await?.finalize()))
   }
 
-  fn cache_path(&self, name: &str) -> String
+  /// Sha256 state after the whole file, read on the blocking pool so other downloads go on.
+  async fn hasher_of(path: &Path) -> Result<Sha256, Error>
   {
-    let path = Path::new(&wd().unwrap_or(String::from("")))
-      .join(FDM_DIRECTORY_NAME)
-      .join(FDM_CACHE_NAME)
-      .join(format!("{}_{}_{}_{}",
-                   name,
-                   self.distribution,
-                   self.version,
-                   self.arch.as_ref().unwrap_or(&PlatformArch::Any))
-        .as_str()
-      ).into_os_string()
-      .into_string()
-      .expect("failed to convert path");
-    path
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+      let mut hasher = Sha256::new();
+      std::io::copy(&mut File::open(&path)?, &mut hasher)?;
+      Ok(hasher)
+    }).await?
   }
 }
\ No newline at end of file
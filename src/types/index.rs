@@ -1,7 +1,7 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use url::Url;
 use crate::types::dependencies::Dependency;
-use crate::types::{Distribution, PlatformArch, Version};
+use crate::types::{Distribution, ExtractLayout, PlatformArch, Version};
 
 #[derive(Debug)]
 pub struct RegistryIndex
@@ -9,10 +9,44 @@ pub struct RegistryIndex
   pub versions: HashMap<Version, Descriptor>
 }
 
+/// A downloadable archive of one distribution/platform of a package version.
+/// `urls` are mirrors of the same file, tried in order.
+#[derive(Debug, Clone)]
+pub struct Artifact
+{
+  pub urls: Vec<Url>,
+  pub sha256: Option<String>,
+  pub layout: ExtractLayout
+}
+
+/// How to build against one distribution of a package version. Paths are relative to the
+/// package directory; library names without a path are looked up in its library directories.
+/// `components` are optional parts of the package, each made of its own libraries.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkInfo
+{
+  pub include: Vec<String>,
+  pub libs: Vec<String>,
+  pub defines: Vec<String>,
+  pub system_libs: Vec<String>,
+  pub components: BTreeMap<String, Vec<String>>
+}
+
+impl LinkInfo
+{
+  pub const KEYS: [&'static str; 5] = ["include", "libs", "defines", "system_libs", "components"];
+
+  pub fn is_empty(&self) -> bool
+  {
+    self == &Self::default()
+  }
+}
+
 #[derive(Debug)]
 pub struct Descriptor
 {
-  pub distribution: HashMap<Distribution, HashMap<PlatformArch, Url>>,
+  pub distribution: HashMap<Distribution, HashMap<PlatformArch, Artifact>>,
+  pub link: HashMap<Distribution, LinkInfo>,
   pub dependencies: HashMap<String, Dependency>
 }
 
@@ -34,6 +68,7 @@ impl Default for Descriptor
     Self
     {
       distribution: HashMap::new(),
+      link: HashMap::new(),
       dependencies: HashMap::new()
     }
   }
@@ -2,17 +2,23 @@ pub mod platform;
 pub mod build_system;
 pub mod distribution;
 pub mod version;
+pub mod requirement;
 pub mod language;
 pub mod dependencies;
 pub mod index;
+pub mod archive;
 
 pub use platform::PlatformArch;
+pub use archive::{ArchiveFormat, ExtractLayout};
 pub use build_system::BuildSystem;
 pub use distribution::Distribution;
 pub use version::Version;
+pub use requirement::VersionReq;
 pub use language::Language;
 pub use index::
 {
   RegistryIndex,
-  Descriptor
+  Descriptor,
+  Artifact,
+  LinkInfo
 };
\ No newline at end of file
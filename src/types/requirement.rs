@@ -0,0 +1,364 @@
+use std::cmp::Ordering;
+use std::fmt::Display;
+use anyhow::{bail, Context};
+use crate::types::Version;
+use crate::types::version::{parse_prerelease, split_version, Identifier};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op
+{
+  Exact,
+  Greater,
+  GreaterEq,
+  Less,
+  LessEq,
+  Tilde,
+  Caret,
+  Wildcard
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comparator
+{
+  pub op: Op,
+  pub major: u32,
+  pub minor: Option<u32>,
+  pub patch: Option<u32>,
+  pub pre: Vec<Identifier>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionReq
+{
+  pub comparators: Vec<Comparator>
+}
+
+impl Default for VersionReq
+{
+  fn default() -> Self
+  {
+    Self::any()
+  }
+}
+
+impl VersionReq
+{
+  pub fn any() -> Self
+  {
+    Self
+    {
+      comparators: Vec::new()
+    }
+  }
+
+  /// Pre-release versions only match when some comparator explicitly names a
+  /// pre-release of the same `MAJOR.MINOR.PATCH`, as cargo does.
+  pub fn matches(&self, version: &Version) -> bool
+  {
+    self.comparators
+      .iter()
+      .all(|cmp| cmp.matches(version))
+      && (!version.is_prerelease() || self.comparators
+        .iter()
+        .any(|cmp| cmp.allows_prerelease_of(version)))
+  }
+}
+
+impl Comparator
+{
+  pub fn matches(&self, version: &Version) -> bool
+  {
+    let lower = Version {
+      pre: self.pre.clone(),
+      ..Version::new(self.major, self.minor.unwrap_or(0), self.patch.unwrap_or(0))
+    };
+    let ord = version.precedence(&lower);
+    match self.op
+    {
+      Op::Exact if self.patch.is_some() => ord == Ordering::Equal,
+      Op::Exact | Op::Wildcard => self.matches_prefix(version),
+      Op::Greater => match (self.minor, self.patch) {
+        (None, _) => version.major > self.major,
+        (Some(minor), None) => (version.major, version.minor) > (self.major, minor),
+        (Some(_), Some(_)) => ord == Ordering::Greater
+      },
+      Op::GreaterEq => ord != Ordering::Less,
+      Op::Less => ord == Ordering::Less,
+      Op::LessEq => match (self.minor, self.patch) {
+        (None, _) => version.major <= self.major,
+        (Some(minor), None) => (version.major, version.minor) <= (self.major, minor),
+        (Some(_), Some(_)) => ord != Ordering::Greater
+      },
+      Op::Tilde => ord != Ordering::Less && match self.minor {
+        None => version.major == self.major,
+        Some(minor) => version.major == self.major && version.minor == minor
+      },
+      Op::Caret => ord != Ordering::Less && match (self.major, self.minor, self.patch) {
+        (0, None, _) => version.major == 0,
+        (0, Some(0), None) => version.major == 0 && version.minor == 0,
+        (0, Some(0), Some(patch)) => version.major == 0 && version.minor == 0 && version.patch == patch,
+        (0, Some(minor), _) => version.major == 0 && version.minor == minor,
+        (major, _, _) => version.major == major
+      }
+    }
+  }
+
+  fn allows_prerelease_of(&self, version: &Version) -> bool
+  {
+    !self.pre.is_empty()
+      && self.major == version.major
+      && self.minor == Some(version.minor)
+      && self.patch == Some(version.patch)
+  }
+
+  fn matches_prefix(&self, version: &Version) -> bool
+  {
+    version.major == self.major
+      && self.minor.is_none_or(|minor| version.minor == minor)
+      && self.patch.is_none_or(|patch| version.patch == patch)
+  }
+}
+
+impl Display for Op
+{
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+  {
+    write!(f, "{}", match self
+    {
+      Op::Exact => "=",
+      Op::Greater => ">",
+      Op::GreaterEq => ">=",
+      Op::Less => "<",
+      Op::LessEq => "<=",
+      Op::Tilde => "~",
+      Op::Caret => "^",
+      Op::Wildcard => ""
+    })
+  }
+}
+
+impl Display for Comparator
+{
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+  {
+    write!(f, "{}{}", self.op, self.major)?;
+    match (self.minor, self.patch)
+    {
+      (None, _) if self.op == Op::Wildcard => write!(f, ".*"),
+      (None, _) => Ok(()),
+      (Some(minor), None) if self.op == Op::Wildcard => write!(f, ".{}.*", minor),
+      (Some(minor), None) => write!(f, ".{}", minor),
+      (Some(minor), Some(patch)) => write!(f, ".{}.{}", minor, patch)
+    }?;
+    if !self.pre.is_empty() {
+      write!(f, "-{}", self.pre
+        .iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<String>>()
+        .join(".")
+      )?;
+    }
+    Ok(())
+  }
+}
+
+impl Display for VersionReq
+{
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+  {
+    if self.comparators.is_empty() {
+      return write!(f, "*");
+    }
+    write!(f, "{}", self.comparators
+      .iter()
+      .map(|cmp| cmp.to_string())
+      .collect::<Vec<String>>()
+      .join(", ")
+    )
+  }
+}
+
+impl TryFrom<&str> for Comparator
+{
+  type Error = anyhow::Error;
+
+  fn try_from(s: &str) -> Result<Self, Self::Error>
+  {
+    let s = s.trim();
+    let (op, rest) = [
+      (">=", Op::GreaterEq),
+      ("<=", Op::LessEq),
+      (">", Op::Greater),
+      ("<", Op::Less),
+      ("=", Op::Exact),
+      ("~", Op::Tilde),
+      ("^", Op::Caret)
+    ]
+      .iter()
+      .find(|(prefix, _)| s.starts_with(prefix))
+      .map(|(prefix, op)| (*op, s[prefix.len()..].trim_start()))
+      .unwrap_or((Op::Caret, s));
+    // build metadata carries no meaning in requirements and is dropped
+    let (core, pre, _) = split_version(rest);
+    let mut parts = core.split('.');
+    let mut wildcard = false;
+    let mut next = |what: &str| -> Result<Option<u32>, anyhow::Error> {
+      match parts.next() {
+        None => Ok(None),
+        Some("*" | "x" | "X") => {
+          wildcard = true;
+          Ok(None)
+        },
+        Some(_) if wildcard => bail!("unexpected {} version after wildcard in requirement: {}", what, s),
+        Some(part) => Ok(Some(part
+          .parse()
+          .with_context(|| format!("invalid {} version in requirement: {}", what, s))?
+        ))
+      }
+    };
+    let major = next("major")?;
+    let minor = next("minor")?;
+    let patch = next("patch")?;
+    if parts.next().is_some() {
+      bail!("too many version components in requirement: {}", s);
+    }
+    let major = match major {
+      Some(major) => major,
+      None => bail!("wildcard major version is only allowed as a standalone requirement: {}", s)
+    };
+    if minor.is_none() && patch.is_some() {
+      bail!("invalid requirement: {}", s);
+    }
+    let pre = match pre {
+      Some(_) if patch.is_none() => bail!("pre-release requires a full MAJOR.MINOR.PATCH version in requirement: {}", s),
+      Some(pre) => parse_prerelease(pre)
+        .with_context(|| format!("invalid pre-release in requirement: {}", s))?,
+      None => Vec::new()
+    };
+    let op = match (wildcard, op) {
+      (true, Op::Caret) if s.starts_with('^') => Op::Caret,
+      (true, Op::Caret | Op::Exact) => Op::Wildcard,
+      (true, _) => bail!("wildcards can only be combined with '=' or '^' in requirement: {}", s),
+      (false, op) => op
+    };
+    Ok(Self
+    {
+      op,
+      major,
+      minor,
+      patch,
+      pre
+    })
+  }
+}
+
+impl TryFrom<&str> for VersionReq
+{
+  type Error = anyhow::Error;
+
+  fn try_from(s: &str) -> Result<Self, Self::Error>
+  {
+    let s = s.trim();
+    if s.is_empty() || s == "*" || s == "x" || s == "X" {
+      return Ok(Self::any());
+    }
+    Ok(Self
+    {
+      comparators: s
+        .split(',')
+        .map(Comparator::try_from)
+        .collect::<Result<Vec<Comparator>, Self::Error>>()?
+    })
+  }
+}
+
+impl TryFrom<String> for VersionReq
+{
+  type Error = anyhow::Error;
+
+  fn try_from(s: String) -> Result<Self, Self::Error>
+  {
+    Self::try_from(s.as_str())
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  fn matches(req: &str, version: &str) -> bool
+  {
+    VersionReq::try_from(req)
+      .unwrap()
+      .matches(&Version::try_from(version).unwrap())
+  }
+
+  #[test]
+  fn caret()
+  {
+    assert!(matches("^0.0.3", "0.0.3"));
+    assert!(!matches("^0.0.3", "0.0.4"));
+    assert!(!matches("^0.0.3", "0.0.2"));
+    assert!(matches("^0.2", "0.2.0"));
+    assert!(matches("^0.2", "0.2.9"));
+    assert!(!matches("^0.2", "0.3.0"));
+    assert!(matches("1.2", "1.9.0"));
+    assert!(!matches("1.2", "2.0.0"));
+    assert!(!matches("1.2", "1.1.9"));
+  }
+
+  #[test]
+  fn tilde()
+  {
+    assert!(matches("~1", "1.0.0"));
+    assert!(matches("~1", "1.9.9"));
+    assert!(!matches("~1", "2.0.0"));
+    assert!(matches("~1.2", "1.2.5"));
+    assert!(!matches("~1.2", "1.3.0"));
+  }
+
+  #[test]
+  fn wildcard()
+  {
+    assert!(matches("1.*", "1.0.0"));
+    assert!(matches("1.*", "1.7.3"));
+    assert!(!matches("1.*", "2.0.0"));
+    assert!(!matches("1.*", "0.9.0"));
+    assert!(matches("*", "3.1.4"));
+    assert!(VersionReq::try_from("~1.*").is_err());
+    assert!(VersionReq::try_from("1.*.3").is_err());
+  }
+
+  #[test]
+  fn ranges()
+  {
+    assert!(matches(">=1.0, <2.0", "1.0.0"));
+    assert!(matches(">=1.0, <2.0", "1.99.0"));
+    assert!(!matches(">=1.0, <2.0", "2.0.0"));
+    assert!(!matches(">=1.0, <2.0", "0.9.9"));
+    assert!(matches("<=1.2", "1.2.7"));
+    assert!(!matches(">1.2", "1.2.7"));
+  }
+
+  #[test]
+  fn prerelease_gating()
+  {
+    assert!(!matches("^1.0.0", "1.0.0-beta"));
+    assert!(!matches(">=0.9", "1.0.0-beta"));
+    assert!(matches("=1.0.0-beta", "1.0.0-beta"));
+    assert!(!matches("=1.0.0-beta", "1.0.0"));
+    assert!(matches("^1.0.0-beta", "1.0.0-beta.2"));
+    assert!(matches("^1.0.0-beta", "1.0.0"));
+    // only pre-releases of the named MAJOR.MINOR.PATCH are allowed
+    assert!(!matches("^1.0.0-beta", "1.1.0-beta"));
+  }
+
+  #[test]
+  fn display_roundtrip()
+  {
+    for req in ["^0.0.3", "~1", "1.*", ">=1.0, <2.0", "=1.0.0-beta", "*"] {
+      assert_eq!(VersionReq::try_from(req).unwrap().to_string(), req);
+    }
+  }
+}
@@ -1,36 +1,115 @@
+use std::cmp::Ordering;
+use anyhow::{bail, ensure};
 use serde::Deserialize;
 
-#[derive(Debug, Clone, PartialEq, Hash, Eq, PartialOrd, Ord, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Hash, Eq)]
+pub enum Identifier
+{
+  Numeric(u64),
+  AlphaNumeric(String)
+}
+
+#[derive(Debug, Clone, PartialEq, Hash, Eq, Deserialize)]
+#[serde(try_from = "String")]
 pub struct Version
 {
   pub major: u32,
   pub minor: u32,
-  pub patch: u32
+  pub patch: u32,
+  pub pre: Vec<Identifier>,
+  pub build: Vec<String>
 }
 
 impl Version
 {
-  #[allow(dead_code)]
   pub fn new(major: u32, minor: u32, patch: u32) -> Self
   {
     Self
     {
       major,
       minor,
-      patch
+      patch,
+      pre: Vec::new(),
+      build: Vec::new()
     }
   }
+
+  pub fn is_prerelease(&self) -> bool
+  {
+    !self.pre.is_empty()
+  }
+
+  /// Compares two versions by semver precedence. Build metadata is ignored.
+  pub fn precedence(&self, other: &Self) -> Ordering
+  {
+    (self.major, self.minor, self.patch)
+      .cmp(&(other.major, other.minor, other.patch))
+      .then_with(|| match (self.pre.is_empty(), other.pre.is_empty()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => self.pre.cmp(&other.pre)
+      })
+  }
 }
 
 impl Default for Version
 {
   fn default() -> Self
   {
-    Self
+    Self::new(0, 0, 0)
+  }
+}
+
+impl PartialOrd for Identifier
+{
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering>
+  {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for Identifier
+{
+  fn cmp(&self, other: &Self) -> Ordering
+  {
+    match (self, other)
     {
-      major: 0,
-      minor: 0,
-      patch: 0
+      (Self::Numeric(a), Self::Numeric(b)) => a.cmp(b),
+      (Self::Numeric(_), Self::AlphaNumeric(_)) => Ordering::Less,
+      (Self::AlphaNumeric(_), Self::Numeric(_)) => Ordering::Greater,
+      (Self::AlphaNumeric(a), Self::AlphaNumeric(b)) => a.cmp(b)
+    }
+  }
+}
+
+impl PartialOrd for Version
+{
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering>
+  {
+    Some(self.cmp(other))
+  }
+}
+
+// build metadata only breaks ties between otherwise equal versions, so that
+// ordering stays consistent with equality
+impl Ord for Version
+{
+  fn cmp(&self, other: &Self) -> Ordering
+  {
+    self.precedence(other)
+      .then_with(|| self.build.cmp(&other.build))
+  }
+}
+
+impl std::fmt::Display for Identifier
+{
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+  {
+    match self
+    {
+      Self::Numeric(n) => write!(f, "{}", n),
+      Self::AlphaNumeric(s) => write!(f, "{}", s)
     }
   }
 }
@@ -39,8 +118,73 @@ impl std::fmt::Display for Version
 {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
   {
-    write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+    if !self.pre.is_empty() {
+      write!(f, "-{}", self.pre
+        .iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<String>>()
+        .join(".")
+      )?;
+    }
+    if !self.build.is_empty() {
+      write!(f, "+{}", self.build.join("."))?;
+    }
+    Ok(())
+  }
+}
+
+fn is_identifier(s: &str) -> bool
+{
+  !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+fn parse_numeric(s: &str) -> Option<u32>
+{
+  if s.is_empty() || !s.chars().all(|c| c.is_ascii_digit()) || (s.len() > 1 && s.starts_with('0')) {
+    return None;
   }
+  s.parse().ok()
+}
+
+/// Splits `1.2.3-pre+build` into its core, pre-release and build parts.
+pub(crate) fn split_version(s: &str) -> (&str, Option<&str>, Option<&str>)
+{
+  let (rest, build) = match s.split_once('+') {
+    Some((rest, build)) => (rest, Some(build)),
+    None => (s, None)
+  };
+  let (core, pre) = match rest.split_once('-') {
+    Some((core, pre)) => (core, Some(pre)),
+    None => (rest, None)
+  };
+  (core, pre, build)
+}
+
+pub(crate) fn parse_prerelease(s: &str) -> Result<Vec<Identifier>, anyhow::Error>
+{
+  s.split('.')
+    .map(|id| {
+      ensure!(is_identifier(id), "invalid pre-release identifier '{}'", id);
+      if id.chars().all(|c| c.is_ascii_digit()) {
+        ensure!(id.len() == 1 || !id.starts_with('0'),
+          "numeric pre-release identifier '{}' must not have leading zeros", id);
+        Ok(Identifier::Numeric(id.parse()?))
+      } else {
+        Ok(Identifier::AlphaNumeric(id.to_string()))
+      }
+    })
+    .collect()
+}
+
+pub(crate) fn parse_build(s: &str) -> Result<Vec<String>, anyhow::Error>
+{
+  s.split('.')
+    .map(|id| {
+      ensure!(is_identifier(id), "invalid build metadata identifier '{}'", id);
+      Ok(id.to_string())
+    })
+    .collect()
 }
 
 impl TryFrom<&str> for Version
@@ -49,12 +193,31 @@ impl TryFrom<&str> for Version
 
   fn try_from(s: &str) -> Result<Self, Self::Error>
   {
-    let mut parts = s.split('.');
+    let invalid = || format!("invalid version '{}': expected MAJOR.MINOR.PATCH[-PRERELEASE][+BUILD]", s);
+    let (core, pre, build) = split_version(s.trim());
+    let parts = core
+      .split('.')
+      .map(parse_numeric)
+      .collect::<Vec<Option<u32>>>();
+    let (major, minor, patch) = match parts.as_slice() {
+      [Some(major), Some(minor), Some(patch)] => (*major, *minor, *patch),
+      _ => bail!(invalid())
+    };
     Ok(Self
     {
-      major: parts.next().unwrap_or("0").parse()?,
-      minor: parts.next().unwrap_or("0").parse()?,
-      patch: parts.next().unwrap_or("0").parse()?,
+      major,
+      minor,
+      patch,
+      pre: pre
+        .map(parse_prerelease)
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("{}: {}", invalid(), e))?
+        .unwrap_or_default(),
+      build: build
+        .map(parse_build)
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("{}: {}", invalid(), e))?
+        .unwrap_or_default()
     })
   }
 }
@@ -67,4 +230,54 @@ impl TryFrom<String> for Version
   {
     Self::try_from(s.as_str())
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  fn version(s: &str) -> Version
+  {
+    Version::try_from(s).unwrap()
+  }
+
+  #[test]
+  fn prerelease_precedence()
+  {
+    // the example chain of the semver spec
+    let ordered = [
+      "1.0.0-alpha",
+      "1.0.0-alpha.1",
+      "1.0.0-alpha.beta",
+      "1.0.0-beta",
+      "1.0.0-beta.2",
+      "1.0.0-beta.11",
+      "1.0.0-rc.1",
+      "1.0.0"
+    ];
+    for pair in ordered.windows(2) {
+      assert_eq!(version(pair[0]).precedence(&version(pair[1])), Ordering::Less, "{} < {}", pair[0], pair[1]);
+    }
+  }
+
+  #[test]
+  fn build_metadata()
+  {
+    let a = version("1.0.0+build.1");
+    let b = version("1.0.0+build.2");
+    assert_eq!(a.precedence(&b), Ordering::Equal);
+    assert_ne!(a, b);
+    assert_eq!(a.cmp(&b), Ordering::Less);
+    assert_eq!(a.build, vec!["build".to_string(), "1".to_string()]);
+    assert_eq!(version("1.0.0-rc.1+001").to_string(), "1.0.0-rc.1+001");
+  }
+
+  #[test]
+  fn rejects_invalid_versions()
+  {
+    for invalid in ["1", "1.0", "01.0.0", "1.00.0", "1.0.0.0", "1.0.0-", "1.0.0-01", "1.0.0-a..b", "1.0.0+", "v1.0.0"] {
+      assert!(Version::try_from(invalid).is_err(), "{} should be rejected", invalid);
+    }
+  }
+}